@@ -0,0 +1,13 @@
+-- SQLite cannot drop columns, so the table is rebuilt without `last_event_id`.
+CREATE TABLE subscriptions_backup(
+    hub_id VARCHAR (50) NOT NULL,
+    session_id INTEGER NOT NULL,
+    subscription_id VARCHAR(50) NOT NULL,
+    CONSTRAINT subscriptions_pk PRIMARY KEY (subscription_id)
+);
+
+INSERT INTO subscriptions_backup SELECT hub_id, session_id, subscription_id FROM subscriptions;
+
+DROP TABLE subscriptions;
+
+ALTER TABLE subscriptions_backup RENAME TO subscriptions;
@@ -0,0 +1 @@
+ALTER TABLE subscriptions ADD COLUMN last_event_id BIGINT NOT NULL DEFAULT -1;
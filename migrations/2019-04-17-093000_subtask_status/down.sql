@@ -0,0 +1,16 @@
+-- SQLite cannot drop columns, so the table is rebuilt without `status`.
+CREATE TABLE subscription_subtask_backup(
+    subscription_id VARCHAR(50) NOT NULL ,
+    task_id VARCHAR(200) NOT NULL,
+    subtask_id VARCHAR2(200) NOT NULL,
+    price_gnt NUMBER,
+    deadline DATETIME,
+    CONSTRAINT subscription_subtask_pk PRIMARY KEY (subscription_id, task_id, subtask_id),
+    CONSTRAINT subscription_subtask_fk1 FOREIGN KEY (subscription_id, task_id) references subscription_tasks(subscription_id, task_id)
+);
+
+INSERT INTO subscription_subtask_backup SELECT subscription_id, task_id, subtask_id, price_gnt, deadline FROM subscription_subtask;
+
+DROP TABLE subscription_subtask;
+
+ALTER TABLE subscription_subtask_backup RENAME TO subscription_subtask;
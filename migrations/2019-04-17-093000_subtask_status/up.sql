@@ -0,0 +1 @@
+ALTER TABLE subscription_subtask ADD COLUMN status VARCHAR(20) NOT NULL DEFAULT 'pending';
@@ -0,0 +1 @@
+DROP TABLE standalone_session;
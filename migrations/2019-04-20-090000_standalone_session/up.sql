@@ -0,0 +1,4 @@
+CREATE TABLE standalone_session(
+    node_id VARCHAR(42) NOT NULL PRIMARY KEY,
+    session_id BIGINT NOT NULL
+);
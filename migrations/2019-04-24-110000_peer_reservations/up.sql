@@ -0,0 +1,9 @@
+CREATE TABLE peer_reservation(
+    node_id VARCHAR(42) NOT NULL,
+    slot INTEGER NOT NULL,
+    task_id VARCHAR(200) NOT NULL,
+    subtask_id VARCHAR(200),
+    reserved_until DATETIME NOT NULL,
+    owner VARCHAR(200),
+    CONSTRAINT peer_reservation_pk PRIMARY KEY (node_id, slot)
+);
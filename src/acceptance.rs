@@ -0,0 +1,209 @@
+//! Policies deciding whether an offered task is taken.
+//!
+//! Every policy either accepts the task or rejects it with a reason, which is
+//! recorded as a `task_rejected` subscription event.
+
+use super::workman::PeerHardware;
+use std::fmt;
+use std::time::Duration;
+
+/// Offered task, as seen by acceptance policies.
+#[derive(Debug, Clone, Default)]
+pub struct TaskInfo {
+    pub task_id: String,
+    /// Unix timestamp in seconds.
+    pub deadline: u64,
+    pub price: Option<f64>,
+    pub resource_size: Option<u64>,
+    pub estimated_memory: Option<u64>,
+}
+
+impl TaskInfo {
+    pub fn from_task(task: &golem_gw_api::models::Task) -> Self {
+        TaskInfo {
+            task_id: task.task_id().to_owned(),
+            deadline: (*task.deadline()) as u64,
+            price: task.price().map(|price| *price as f64),
+            resource_size: task.resource_size().map(|size| *size as u64),
+            estimated_memory: task.estimated_memory().map(|memory| *memory as u64),
+        }
+    }
+}
+
+/// Mediator state at the time the task is offered.
+#[derive(Debug, Clone, Default)]
+pub struct Capacity {
+    /// Unix timestamp in seconds.
+    pub now: u64,
+    pub running_tasks: usize,
+    pub peers: Vec<PeerHardware>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection(pub String);
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+pub trait AcceptancePolicy {
+    fn check(&self, task: &TaskInfo, capacity: &Capacity) -> Result<(), Rejection>;
+}
+
+/// Task is accepted only when all policies accept it; first rejection wins.
+impl AcceptancePolicy for Vec<Box<dyn AcceptancePolicy>> {
+    fn check(&self, task: &TaskInfo, capacity: &Capacity) -> Result<(), Rejection> {
+        self.iter().map(|policy| policy.check(task, capacity)).collect()
+    }
+}
+
+/// Rejects tasks with less than `min_time_left` to their deadline.
+pub struct DeadlinePolicy {
+    pub min_time_left: Duration,
+}
+
+impl AcceptancePolicy for DeadlinePolicy {
+    fn check(&self, task: &TaskInfo, capacity: &Capacity) -> Result<(), Rejection> {
+        let time_left = task.deadline.saturating_sub(capacity.now);
+        if time_left < self.min_time_left.as_secs() {
+            return Err(Rejection(format!(
+                "deadline too close: {}s left, {}s required",
+                time_left,
+                self.min_time_left.as_secs()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Rejects tasks paying less than `min_price`. Tasks without price are accepted.
+pub struct MinPricePolicy {
+    pub min_price: f64,
+}
+
+impl AcceptancePolicy for MinPricePolicy {
+    fn check(&self, task: &TaskInfo, _capacity: &Capacity) -> Result<(), Rejection> {
+        match task.price {
+            Some(price) if price < self.min_price => Err(Rejection(format!(
+                "price {} below {}",
+                price, self.min_price
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Rejects tasks whose estimated memory does not fit on any peer.
+/// Peers with unknown memory are assumed to fit.
+pub struct MemoryFitPolicy;
+
+impl AcceptancePolicy for MemoryFitPolicy {
+    fn check(&self, task: &TaskInfo, capacity: &Capacity) -> Result<(), Rejection> {
+        let estimated_memory = match task.estimated_memory {
+            Some(estimated_memory) => estimated_memory,
+            None => return Ok(()),
+        };
+        if capacity.peers.is_empty()
+            || capacity
+                .peers
+                .iter()
+                .any(|p| p.memory == 0 || p.memory >= estimated_memory)
+        {
+            return Ok(());
+        }
+        Err(Rejection(format!(
+            "estimated memory {} exceeds every peer",
+            estimated_memory
+        )))
+    }
+}
+
+/// Rejects tasks when `max_tasks` tasks are already running.
+pub struct ConcurrencyPolicy {
+    pub max_tasks: usize,
+}
+
+impl AcceptancePolicy for ConcurrencyPolicy {
+    fn check(&self, _task: &TaskInfo, capacity: &Capacity) -> Result<(), Rejection> {
+        if capacity.running_tasks >= self.max_tasks {
+            return Err(Rejection(format!(
+                "{} tasks already running",
+                capacity.running_tasks
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn task() -> TaskInfo {
+        TaskInfo {
+            task_id: "t1".into(),
+            deadline: 2000,
+            price: Some(5.0),
+            resource_size: None,
+            estimated_memory: Some(4_000_000_000),
+        }
+    }
+
+    fn capacity() -> Capacity {
+        Capacity {
+            now: 1000,
+            running_tasks: 1,
+            peers: vec![
+                PeerHardware { cores: 4, memory: 2_000_000_000, ..PeerHardware::default() },
+                PeerHardware { cores: 8, memory: 8_000_000_000, ..PeerHardware::default() },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_deadline() {
+        let policy = DeadlinePolicy { min_time_left: Duration::from_secs(600) };
+        assert!(policy.check(&task(), &capacity()).is_ok());
+
+        let late = Capacity { now: 1500, ..capacity() };
+        assert!(policy.check(&task(), &late).is_err());
+        let expired = Capacity { now: 3000, ..capacity() };
+        assert!(policy.check(&task(), &expired).is_err());
+    }
+
+    #[test]
+    fn test_min_price() {
+        assert!(MinPricePolicy { min_price: 5.0 }.check(&task(), &capacity()).is_ok());
+        assert!(MinPricePolicy { min_price: 6.0 }.check(&task(), &capacity()).is_err());
+
+        let no_price = TaskInfo { price: None, ..task() };
+        assert!(MinPricePolicy { min_price: 6.0 }.check(&no_price, &capacity()).is_ok());
+    }
+
+    #[test]
+    fn test_memory_fit() {
+        assert!(MemoryFitPolicy.check(&task(), &capacity()).is_ok());
+
+        let big = TaskInfo { estimated_memory: Some(16_000_000_000), ..task() };
+        assert!(MemoryFitPolicy.check(&big, &capacity()).is_err());
+        assert!(MemoryFitPolicy.check(&big, &Capacity::default()).is_ok());
+    }
+
+    #[test]
+    fn test_policies() {
+        let policies: Vec<Box<dyn AcceptancePolicy>> = vec![
+            Box::new(MinPricePolicy { min_price: 1.0 }),
+            Box::new(ConcurrencyPolicy { max_tasks: 1 }),
+        ];
+
+        assert_eq!(
+            policies.check(&task(), &capacity()),
+            Err(Rejection("1 tasks already running".into()))
+        );
+        assert!(policies
+            .check(&task(), &Capacity { running_tasks: 0, ..capacity() })
+            .is_ok());
+    }
+}
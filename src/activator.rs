@@ -1,18 +1,134 @@
 use actix::prelude::*;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
+use std::time::Duration;
 use gu_client::r#async::HubConnection;
-use crate::gateway::Gateway;
-use futures::Future;
+use gu_client::model::session::{AllocationMode, HubSessionSpec};
+use crate::gateway::{self, Gateway};
+use futures::{future, Future};
+use crate::blender::ImageCatalogue;
 use crate::error::Error;
+use crate::model;
+use crate::keystore::NodeIdentity;
+use crate::workman::SelectionStrategy;
+use serde_derive::*;
 
+/// Tag of hub sessions managed by this mediator.
+pub const SESSION_TAG : &str = "gu:brass:taskType=Blender";
+/// Tag of the hub session created by the standalone gateway.
+pub const STANDALONE_TAG : &str = "gu:brass:standalone";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionConfig {
+    // "account":"0xb2bbb75241939e50b5ba6f698415bbb5ca54610d","davUrl":"http://127.0.0.1:55011","docker":true,"gwUrl":"http://127.0.0.1:55001/"
+    pub account: String,
+    pub dav_url: String,
+    pub gw_url: String,
+    pub docker: bool,
+    #[serde(default)]
+    pub subscription_id: String,
+    #[serde(default)]
+    pub status: Option<String>,
+    /// New tasks are not taken; kept apart from `status`, which follows the gateway connection.
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
+    pub images: Option<ImageCatalogue>,
+    /// Maximal number of peers computing subtasks of a single task at once.
+    #[serde(default = "default_max_peers_per_task")]
+    pub max_peers_per_task: usize,
+    /// Subtasks are aborted this many seconds before their deadline.
+    #[serde(default = "default_deadline_margin_secs")]
+    pub deadline_margin_secs: u64,
+    /// Tasks with less seconds left to their deadline are not taken.
+    #[serde(default = "default_min_time_left_secs")]
+    pub min_time_left_secs: u64,
+    /// Peers added to hub session created in standalone mode; all hub peers when empty.
+    #[serde(default)]
+    pub peers: Vec<gu_client::NodeId>,
+    /// Subscription parameters; unset ones are derived from session peers.
+    #[serde(default)]
+    pub min_price: Option<f64>,
+    #[serde(default)]
+    pub max_cpu_cores: Option<u32>,
+    #[serde(default)]
+    pub max_memory: Option<u64>,
+    #[serde(default)]
+    pub max_disk: Option<u64>,
+    #[serde(default)]
+    pub performance: Option<f32>,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Maximal number of tasks computed at once.
+    #[serde(default)]
+    pub max_tasks: Option<usize>,
+    /// How peers are picked for subtasks.
+    #[serde(default)]
+    pub peer_selection: SelectionStrategy,
+    /// Subtasks a single peer computes at once; derived from peer hardware when unset.
+    #[serde(default)]
+    pub slots_per_peer: Option<usize>,
+}
+
+fn default_max_peers_per_task() -> usize {
+    4
+}
+
+fn default_deadline_margin_secs() -> u64 {
+    30
+}
+
+fn default_min_time_left_secs() -> u64 {
+    300
+}
+
+impl SessionConfig {
+    pub fn from_metadata(m : gu_client::model::session::Metadata) -> Result<SessionConfig, Error> {
+        Ok(serde_json::from_value(serde_json::to_value(m.entry)?)?)
+    }
+
+    /// Session should be active: working, paused or reconnecting to the gateway.
+    pub fn is_working(&self) -> bool {
+        match self.status.as_ref().map(String::as_str) {
+            Some("working") | Some("paused") | Some("degraded") | Some("reconnecting") => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused || self.status.as_ref().map(|s| s == "paused").unwrap_or(false)
+    }
+
+    /// Time left required to take a task; at least twice the abort margin, so
+    /// subtasks are not aborted as soon as they start.
+    pub fn min_time_left(&self) -> Duration {
+        Duration::from_secs(self.min_time_left_secs.max(2 * self.deadline_margin_secs))
+    }
+}
+
+/// Registry of running gateways, one per hub session.
+#[derive(Clone)]
 pub struct Activator {
-    gateways : RwLock<HashMap<u64, Addr<Gateway>>>,
+    gateways : Arc<RwLock<HashMap<u64, Addr<Gateway>>>>,
     hub_connection : HubConnection,
+    identity : NodeIdentity,
+    images : Arc<ImageCatalogue>,
+    grace_period : Duration,
 }
 
 impl Activator {
 
+    pub fn new(hub_connection : HubConnection, identity : NodeIdentity, images : ImageCatalogue, grace_period : Duration) -> Self {
+        Activator {
+            gateways: Arc::new(RwLock::new(HashMap::new())),
+            hub_connection,
+            identity,
+            images: Arc::new(images),
+            grace_period,
+        }
+    }
+
     pub fn session_gateway(&self, session_id : u64) -> Option<Addr<Gateway>> {
         let result = {
             self.gateways.read().unwrap().get(&session_id).map(|addr| addr.clone())
@@ -46,11 +162,170 @@ impl Activator {
         self.gateways.read().unwrap().keys().cloned().collect()
     }
 
+    /// Starts gateway for the session. Already running gateway is returned as is.
     pub fn activate_gateway(&self, session_id : u64) -> impl Future<Item=Addr<Gateway>, Error=Error> {
-        self.hub_connection.hub_session(session_id).config().map_err(|e| Error::Other(format!("{}", e))).and_then(|m| {
-            //serde_json::from_value(serde_json::to_value(m.entry)?).map_err(Error::from)
-            Ok(unimplemented!())
+        if let Some(gw) = self.session_gateway(session_id) {
+            log::info!("gateway for session {} already running", session_id);
+            return future::Either::A(future::ok(gw));
+        }
+
+        let gateways = self.gateways.clone();
+        let identity = self.identity.clone();
+        let images = self.images.clone();
+
+        future::Either::B(self.hub_connection.hub_session(session_id).config().map_err(|e| Error::Other(format!("{}", e))).and_then(move |m| {
+            let config = SessionConfig::from_metadata(m)?;
+
+            let mut w = gateways.write().unwrap();
+            if let Some(gw) = w.get(&session_id).filter(|gw| gw.connected()) {
+                return Ok(gw.clone());
+            }
+
+            // gateway subscription belongs to the node, it can not be shared by sessions
+            if let Some(other) = w.iter().find(|(id, gw)| **id != session_id && gw.connected()).map(|(id, _)| *id) {
+                return Err(Error::Other(format!("node {} already serves session {}", identity.node_id, other)));
+            }
+
+            log::info!("starting gateway for session {}, gw={}", session_id, config.gw_url);
+            let gw = Gateway::new(session_id, false, config, identity, images)?.start();
+            w.insert(session_id, gw.clone());
+            Ok(gw)
+        }))
+    }
+
+    /// Starts gateway in standalone mode. It uses its own hub session, created once and reused on restart, so it is not bound to any session started from the UI.
+    pub fn activate_standalone(&self, config : SessionConfig) -> impl Future<Item=Addr<Gateway>, Error=Error> {
+        let gateways = self.gateways.clone();
+        let identity = self.identity.clone();
+        let images = self.images.clone();
+        let name = config.name.clone().unwrap_or_else(|| gateway::DEFAULT_SESSION_NAME.into());
+
+        self.standalone_session(name).and_then(move |session_id| {
+            log::info!("starting standalone gateway for session {}, gw={}", session_id, config.gw_url);
+            let gw = Gateway::new(session_id, true, config, identity, images)?.start();
+            gateways.write().unwrap().insert(session_id, gw.clone());
+            Ok(gw)
+        })
+    }
+
+    /// Hub session of the standalone gateway. Session created by previous run is reused as long as the hub still has it, so deployments and subscription of the node can be restored.
+    fn standalone_session(&self, name : String) -> impl Future<Item=u64, Error=Error> {
+        let stored = match model::establish_connection().and_then(|db| {
+            model::find_standalone_session(&db, &self.identity.node_id).map_err(|e| Error::Database(e.to_string()))
+        }) {
+            Ok(stored) => stored,
+            Err(e) => return future::Either::A(future::err(e)),
+        };
+
+        let hub_connection = self.hub_connection.clone();
+        let node_id = self.identity.node_id.clone();
+        let spec = HubSessionSpec {
+            expires: None,
+            allocation: AllocationMode::AUTO,
+            name: Some(name),
+            tags: std::iter::once(STANDALONE_TAG.to_string()).collect(),
+        };
+        let create_session = move || {
+            hub_connection.new_session(spec)
+                .map(|h| h.into_inner().unwrap().id())
+                .map_err(|e| Error::Other(format!("failed to create standalone hub session: {}", e)))
+                .and_then(move |session_id| {
+                    model::establish_connection()
+                        .and_then(|db| model::store_standalone_session(&db, &node_id, session_id).map_err(|e| Error::Database(e.to_string())))?;
+                    log::info!("standalone hub session {} created", session_id);
+                    Ok(session_id)
+                })
+        };
+
+        future::Either::B(match stored {
+            Some(session_id) => future::Either::A(self.hub_connection.hub_session(session_id).config().then(move |r| match r {
+                Ok(_) => {
+                    log::info!("reusing standalone hub session {}", session_id);
+                    future::Either::A(future::ok(session_id))
+                }
+                Err(gu_client::error::Error::ResponseErr(status)) if status.as_u16() == 404 => {
+                    log::warn!("standalone hub session {} is gone", session_id);
+                    future::Either::B(create_session())
+                }
+                Err(e) => future::Either::A(future::err(Error::Other(format!("unable to check standalone hub session {}: {}", session_id, e)))),
+            })),
+            None => future::Either::B(create_session()),
+        })
+    }
+
+    /// Drains all running gateways, giving in-flight subtasks `grace_period` to finish.
+    pub fn shutdown(&self, grace_period : Duration) -> impl Future<Item=(), Error=()> {
+        let gateways : Vec<Addr<Gateway>> = self.gateways.write().unwrap()
+            .drain()
+            .map(|(_, gw)| gw)
+            .filter(|gw| gw.connected())
+            .collect();
+
+        future::join_all(gateways.into_iter().map(move |gw| {
+            gw.send(gateway::DrainSession { grace_period }).flatten().then(|r| {
+                if let Err(e) = r {
+                    log::error!("gateway shutdown failed: {}", e);
+                }
+                Ok::<(), ()>(())
+            })
+        })).map(|_| ())
+    }
+
+    /// Marks the session stopped and drains its gateway, giving in-flight subtasks the grace period to finish.
+    pub fn stop_gateway(&self, session_id : u64) -> impl Future<Item=(), Error=Error> {
+        let gw = self.gateways.write().unwrap().remove(&session_id);
+        let grace_period = self.grace_period;
+
+        match gw {
+            Some(gw) => future::Either::A(gw.send(gateway::Shutdown { grace_period }).flatten()),
+            None => future::Either::B(future::err(Error::Other(format!("session {} not active", session_id)))),
+        }
+    }
+
+    pub fn pause_gateway(&self, session_id : u64) -> impl Future<Item=(), Error=Error> {
+        match self.session_gateway(session_id) {
+            Some(gw) => future::Either::A(gw.send(gateway::Pause).from_err()),
+            None => future::Either::B(future::err(Error::Other(format!("session {} not active", session_id)))),
+        }
+    }
+
+    pub fn resume_gateway(&self, session_id : u64) -> impl Future<Item=(), Error=Error> {
+        match self.session_gateway(session_id) {
+            Some(gw) => future::Either::A(gw.send(gateway::Resume).from_err()),
+            None => future::Either::B(future::err(Error::Other(format!("session {} not active", session_id)))),
+        }
+    }
+
+    /// Reactivates gateways for every blender hub session that was working before restart.
+    pub fn restore_sessions(&self) -> impl Future<Item=(), Error=Error> {
+        let activator = self.clone();
+
+        self.hub_connection.list_sessions().map_err(|e| Error::Other(format!("{}", e))).and_then(move |sessions| {
+            let session_ids : Vec<u64> = sessions
+                .into_iter()
+                .filter(|s| s.spec.tags.contains(SESSION_TAG))
+                .map(|s| s.id)
+                .collect();
+
+            future::join_all(session_ids.into_iter().map(move |session_id| {
+                let activator = activator.clone();
+
+                activator.hub_connection.hub_session(session_id).config()
+                    .map_err(|e| Error::Other(format!("{}", e)))
+                    .and_then(|m| SessionConfig::from_metadata(m))
+                    .and_then(move |config| if config.is_working() {
+                        future::Either::A(activator.activate_gateway(session_id).map(|_| ()))
+                    } else {
+                        future::Either::B(future::ok(()))
+                    })
+                    .then(move |r| {
+                        if let Err(e) = r {
+                            log::error!("unable to restore session {}: {}", session_id, e);
+                        }
+                        Ok(())
+                    })
+            })).and_then(|_ : Vec<()>| Ok(()))
         })
     }
 
-}
\ No newline at end of file
+}
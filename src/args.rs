@@ -1,13 +1,259 @@
+use super::activator::SessionConfig;
+use super::error::Error;
+use serde_derive::*;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use structopt::StructOpt;
 
+const DEFAULT_LISTEN_PORT: u16 = 33433;
+const DEFAULT_BIND_ADDR: &str = "127.0.0.1";
+const DEFAULT_GRACE_PERIOD_SECS: u64 = 30;
+
 #[derive(StructOpt, Debug)]
 pub struct Args {
+    /// Port of the HTTP API; 0 selects the default 33433.
     #[structopt(short = "p", long = "listen-port", default_value = "0")]
     pub listen_port: u16,
 
+    /// Address the HTTP API binds to (default 127.0.0.1).
+    #[structopt(long = "bind")]
+    pub bind: Option<String>,
+
     #[structopt(long = "local")]
     pub local: bool,
 
+    /// Directory holding node key and database.
     #[structopt(short="s", long = "work-dir", default_value = "")]
     pub work_dir : String,
+
+    /// Password protecting node key stored in work dir.
+    #[structopt(long = "key-password", env = "GU_MEDIATOR_KEY_PASSWORD", default_value = "")]
+    pub key_password : String,
+
+    /// Allow storing a new node key without password.
+    #[structopt(long = "insecure-key")]
+    pub insecure_key : bool,
+
+    /// JSON file with blender images catalogue.
+    #[structopt(long = "images")]
+    pub images : Option<String>,
+
+    /// JSON file with mediator settings. Command line options take precedence.
+    #[structopt(short = "c", long = "config")]
+    pub config : Option<String>,
+
+    /// Brass gateway url. When given, a gateway is started at once, without
+    /// waiting for a session from the plugin UI.
+    #[structopt(long = "gw")]
+    pub gw_url : Option<String>,
+
+    /// DAV storage url of the gateway (default: `<gw>/dav`).
+    #[structopt(long = "dav")]
+    pub dav_url : Option<String>,
+
+    /// Ethereum account receiving payments (default: node address).
+    #[structopt(long = "account")]
+    pub account : Option<String>,
+
+    /// Run blender in docker instead of the hd environment.
+    #[structopt(long = "docker")]
+    pub docker : bool,
+
+    /// Hub peer used for computations; may be repeated. All hub peers are used when none given.
+    #[structopt(long = "peer")]
+    pub peers : Vec<String>,
+
+    /// Seconds running subtasks are given to finish on shutdown (default 30).
+    #[structopt(long = "grace-period")]
+    pub grace_period : Option<u64>,
+}
+
+/// Settings read from `--config` file.
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub listen_port: Option<u16>,
+    #[serde(default)]
+    pub bind: Option<String>,
+    #[serde(default)]
+    pub local: Option<bool>,
+    #[serde(default)]
+    pub work_dir: Option<String>,
+    #[serde(default)]
+    pub key_password: Option<String>,
+    #[serde(default)]
+    pub insecure_key: Option<bool>,
+    #[serde(default)]
+    pub images: Option<String>,
+    #[serde(default)]
+    pub gw_url: Option<String>,
+    #[serde(default)]
+    pub dav_url: Option<String>,
+    #[serde(default)]
+    pub account: Option<String>,
+    #[serde(default)]
+    pub docker: Option<bool>,
+    #[serde(default)]
+    pub peers: Vec<String>,
+    #[serde(default)]
+    pub grace_period: Option<u64>,
+}
+
+impl ConfigFile {
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let f = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(f)?)
+    }
+}
+
+impl Args {
+    /// Parses command line and fills options not given there from `--config` file.
+    pub fn load() -> Result<Args, Error> {
+        let args = Args::from_args();
+
+        match &args.config {
+            Some(path) => {
+                let config = ConfigFile::from_file(Path::new(path))?;
+                Ok(args.merge(config))
+            }
+            None => Ok(args),
+        }
+    }
+
+    fn merge(mut self, config: ConfigFile) -> Self {
+        if self.listen_port == 0 {
+            self.listen_port = config.listen_port.unwrap_or(0);
+        }
+        if self.bind.is_none() {
+            self.bind = config.bind;
+        }
+        if !self.local {
+            self.local = config.local.unwrap_or(false);
+        }
+        if self.work_dir.is_empty() {
+            self.work_dir = config.work_dir.unwrap_or_default();
+        }
+        if self.key_password.is_empty() {
+            self.key_password = config.key_password.unwrap_or_default();
+        }
+        if !self.insecure_key {
+            self.insecure_key = config.insecure_key.unwrap_or(false);
+        }
+        if self.images.is_none() {
+            self.images = config.images;
+        }
+        if self.gw_url.is_none() {
+            self.gw_url = config.gw_url;
+        }
+        if self.dav_url.is_none() {
+            self.dav_url = config.dav_url;
+        }
+        if self.account.is_none() {
+            self.account = config.account;
+        }
+        if !self.docker {
+            self.docker = config.docker.unwrap_or(false);
+        }
+        if self.peers.is_empty() {
+            self.peers = config.peers;
+        }
+        if self.grace_period.is_none() {
+            self.grace_period = config.grace_period;
+        }
+        self
+    }
+
+    /// Session config of the standalone gateway; `None` when no gateway url is given.
+    pub fn standalone_config(&self, default_account: &str) -> Result<Option<SessionConfig>, Error> {
+        let gw_url = match &self.gw_url {
+            Some(gw_url) => gw_url,
+            None => return Ok(None),
+        };
+        let dav_url = self
+            .dav_url
+            .clone()
+            .unwrap_or_else(|| format!("{}/dav", gw_url.trim_end_matches('/')));
+
+        Ok(Some(serde_json::from_value(serde_json::json!({
+            "account": self.account.as_ref().map(String::as_str).unwrap_or(default_account),
+            "davUrl": dav_url,
+            "gwUrl": gw_url,
+            "docker": self.docker,
+            "status": "working",
+            "peers": self.peers,
+        }))?))
+    }
+
+    pub fn listen_port(&self) -> u16 {
+        if self.listen_port == 0 {
+            DEFAULT_LISTEN_PORT
+        } else {
+            self.listen_port
+        }
+    }
+
+    pub fn bind_addr(&self) -> &str {
+        self.bind.as_ref().map(String::as_str).unwrap_or(DEFAULT_BIND_ADDR)
+    }
+
+    pub fn listen_addr(&self) -> String {
+        format!("{}:{}", self.bind_addr(), self.listen_port())
+    }
+
+    /// Url under which the HTTP API is registered in the hub.
+    pub fn public_url(&self) -> String {
+        let host = match self.bind_addr() {
+            "0.0.0.0" | "::" => DEFAULT_BIND_ADDR,
+            addr => addr,
+        };
+        format!("http://{}:{}/", host, self.listen_port())
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period.unwrap_or(DEFAULT_GRACE_PERIOD_SECS))
+    }
+
+    pub fn work_dir(&self) -> PathBuf {
+        PathBuf::from(&self.work_dir)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let args = Args::from_iter(&["gu-blender-mediator", "--listen-port", "40000"]);
+        let config: ConfigFile = serde_json::from_str(
+            r#"{"listenPort": 50000, "bind": "0.0.0.0", "workDir": "/var/lib/mediator"}"#,
+        )
+        .unwrap();
+
+        let args = args.merge(config);
+        assert_eq!(args.listen_addr(), "0.0.0.0:40000");
+        assert_eq!(args.public_url(), "http://127.0.0.1:40000/");
+        assert_eq!(args.work_dir(), PathBuf::from("/var/lib/mediator"));
+
+        let args = Args::from_iter(&["gu-blender-mediator"]).merge(ConfigFile::default());
+        assert_eq!(args.listen_addr(), "127.0.0.1:33433");
+        assert!(args.standalone_config("0x00").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_standalone_config() {
+        let args = Args::from_iter(&["gu-blender-mediator", "--gw", "http://gw:55001/", "--docker"]);
+
+        let config = args.standalone_config("0xb2bb").unwrap().unwrap();
+        assert_eq!(config.dav_url, "http://gw:55001/dav");
+        assert_eq!(config.account, "0xb2bb");
+        assert!(config.docker);
+        assert!(config.is_working());
+        assert_eq!(config.min_time_left(), Duration::from_secs(300));
+
+        let args = Args::from_iter(&["gu-blender-mediator", "--gw", "http://gw:55001"]);
+        let config = args.standalone_config("0xb2bb").unwrap().unwrap();
+        assert_eq!(config.dav_url, "http://gw:55001/dav");
+    }
 }
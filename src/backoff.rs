@@ -0,0 +1,81 @@
+use rand::Rng as _;
+use std::time::Duration;
+
+/// Exponential backoff with jitter.
+///
+/// Every consecutive failure doubles the base delay up to `max`. The returned
+/// delay is picked at random from the upper half of the base delay, so
+/// mediators failing at the same time do not retry in lockstep.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            failures: 0,
+        }
+    }
+
+    /// Number of failures since last success.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Records failure and returns delay before next attempt.
+    pub fn fail(&mut self) -> Duration {
+        let base = self.base_delay();
+        self.failures = self.failures.saturating_add(1);
+
+        let base_ms = duration_ms(base);
+        let half_ms = base_ms / 2;
+        Duration::from_millis(half_ms + rand::thread_rng().gen_range(0, base_ms - half_ms + 1))
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    fn base_delay(&self) -> Duration {
+        let factor = 1u32.checked_shl(self.failures.min(31)).unwrap_or(u32::max_value());
+        self.initial
+            .checked_mul(factor)
+            .map(|delay| delay.min(self.max))
+            .unwrap_or(self.max)
+    }
+}
+
+fn duration_ms(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
+
+        let bounds = [(500, 1000), (1000, 2000), (2000, 4000), (4000, 8000), (8000, 16000)];
+        for &(min, max) in bounds.iter() {
+            let delay = duration_ms(backoff.fail());
+            assert!(delay >= min && delay <= max, "{} not in [{}, {}]", delay, min, max);
+        }
+
+        for _ in 0..100 {
+            let delay = duration_ms(backoff.fail());
+            assert!(delay >= 15000 && delay <= 30000);
+        }
+        assert_eq!(backoff.failures(), 105);
+
+        backoff.reset();
+        assert_eq!(backoff.failures(), 0);
+        assert!(duration_ms(backoff.fail()) <= 1000);
+    }
+}
@@ -1,9 +1,10 @@
+use std::collections::HashSet;
 use std::io;
 
 use failure::Fail;
 use futures::{future, prelude::*};
 use gu_client::model::envman::{CreateSession, Image};
-use gu_client::r#async::{Peer, PeerSession};
+use gu_client::r#async::{HubSession, Peer, PeerSession};
 use serde_derive::*;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -104,7 +105,11 @@ impl OldBlenderTaskSpec {
             resolution: (data.resolution_x()?, data.resolution_y()?),
             frames: self.frames,
             scene_file: self.scene_file,
-            output_format: self.output_format,
+            output_format: {
+                let _: OutputFormat = self.output_format.parse()?;
+                self.output_format
+            },
+            blender_version: None,
             crops: vec![Crop {
                 borders_x: (data.border_min_x()?, data.border_max_x()?),
                 borders_y: (data.border_min_y()?, data.border_max_y()?),
@@ -114,6 +119,56 @@ impl OldBlenderTaskSpec {
     }
 }
 
+#[derive(Debug, Fail)]
+#[fail(display = "unsupported output format: {}", _0)]
+pub struct UnsupportedOutputFormat(String);
+
+/// Image formats blender can render to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    Exr,
+    Jpeg,
+    Tga,
+    Bmp,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Exr => "exr",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Tga => "tga",
+            OutputFormat::Bmp => "bmp",
+        }
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = UnsupportedOutputFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_uppercase().as_str() {
+            "PNG" => OutputFormat::Png,
+            "EXR" | "OPEN_EXR" => OutputFormat::Exr,
+            "JPEG" | "JPG" => OutputFormat::Jpeg,
+            "TGA" | "TARGA" => OutputFormat::Tga,
+            "BMP" => OutputFormat::Bmp,
+            _ => return Err(UnsupportedOutputFormat(s.to_owned())),
+        })
+    }
+}
+
+/// Accepts only formats known to `OutputFormat`, but keeps the requestor's spelling.
+fn validate_output_format<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+    let _: OutputFormat = s.parse().map_err(serde::de::Error::custom)?;
+    Ok(s)
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BlenderSubtaskSpec {
     crops: Vec<Crop>,
@@ -121,7 +176,10 @@ pub struct BlenderSubtaskSpec {
     resolution: (u32, u32),
     frames: Vec<u32>,
     scene_file: Option<String>,
+    #[serde(deserialize_with = "validate_output_format")]
     output_format: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blender_version: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -142,17 +200,28 @@ impl BlenderSubtaskSpec {
         });
     }
 
-    pub fn expected_output_file_name(&self) -> String {
+    pub fn blender_version(&self) -> Option<&str> {
+        self.blender_version.as_ref().map(|v| v.as_str())
+    }
+
+    fn output_format(&self) -> OutputFormat {
+        self.output_format
+            .parse()
+            .expect("output format validated on decode")
+    }
+
+    /// Names of files rendered for every frame and crop of the subtask.
+    pub fn expected_output_file_names(&self) -> Vec<String> {
+        let extension = self.output_format().extension();
         self.frames
             .iter()
             .map(|&frame| {
                 self.crops
                     .iter()
-                    .map(move |c| format!("{}{:04}.png", c.outfilebasename, frame))
+                    .map(move |c| format!("{}{:04}.{}", c.outfilebasename, frame, extension))
             })
             .flatten()
-            .next()
-            .unwrap()
+            .collect()
     }
 }
 
@@ -168,19 +237,136 @@ impl std::fmt::Display for BlenderSubtaskSpec {
     }
 }
 
+/// Tag of peer deployments created by the mediator.
+pub const BLENDER_TAG: &str = "gu:blender";
+
+/// Tag of deployments created by the mediator node within the hub session.
+pub fn owner_tag(node_id: &str, session_id: u64) -> String {
+    format!("gu:brass:owner={}/{}", node_id, session_id)
+}
+
+/// Tag of deployments computing subtasks of the task.
+pub fn task_tag(task_id: &str) -> String {
+    format!("gu:brass:task={}", task_id)
+}
+
+/// Tags of blender deployment created for the task.
+pub fn deployment_tags(node_id: &str, session_id: u64, task_id: &str) -> Vec<String> {
+    vec![
+        "gu:render".into(),
+        BLENDER_TAG.into(),
+        owner_tag(node_id, session_id),
+        task_tag(task_id),
+    ]
+}
+
+pub fn env_type(docker: bool) -> &'static str {
+    if docker {
+        "docker"
+    } else {
+        "hd"
+    }
+}
+
+/// Blender environment image available for deployment.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageSpec {
+    pub blender_version: String,
+    pub env_type: String,
+    pub url: String,
+    pub hash: String,
+    #[serde(default)]
+    pub volumes: Vec<VolumeSpec>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VolumeSpec {
+    pub src: String,
+    pub target: String,
+}
+
+impl ImageSpec {
+    pub fn is_docker(&self) -> bool {
+        self.env_type == env_type(true)
+    }
+}
+
+/// Images known to the mediator, in order of preference.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageCatalogue {
+    images: Vec<ImageSpec>,
+}
+
+impl Default for ImageCatalogue {
+    fn default() -> Self {
+        ImageCatalogue {
+            images: vec![
+                ImageSpec {
+                    blender_version: "2.79".into(),
+                    env_type: env_type(false).into(),
+                    url: "http://52.31.143.91/images/x86_64/linux/gu-blender.hdi".into(),
+                    hash: "SHA1:213fad4e020ded42e6a949f61cb660cb69bc9845".into(),
+                    volumes: Vec::new(),
+                },
+                ImageSpec {
+                    blender_version: "2.79".into(),
+                    env_type: env_type(true).into(),
+                    url: "prekucki/gu-render-blender".into(),
+                    hash: "sha256:53d11e6866835986b625e9fb07aa73b31dc667da39fe04f56da0ef06a50e0083"
+                        .into(),
+                    volumes: vec![
+                        VolumeSpec {
+                            src: "resources".into(),
+                            target: "/golem/resources".into(),
+                        },
+                        VolumeSpec {
+                            src: "output".into(),
+                            target: "/golem/output".into(),
+                        },
+                    ],
+                },
+            ],
+        }
+    }
+}
+
+impl ImageCatalogue {
+    pub fn from_file(path: &std::path::Path) -> Result<ImageCatalogue, crate::error::Error> {
+        Ok(serde_json::from_reader(std::fs::File::open(path)?)?)
+    }
+
+    /// Catalogue with own entries preferred over `other` ones.
+    pub fn merge(mut self, other: &ImageCatalogue) -> ImageCatalogue {
+        self.images.extend(other.images.iter().cloned());
+        self
+    }
+
+    /// First image for given environment type, optionally restricted to blender version.
+    pub fn find(&self, docker: bool, blender_version: Option<&str>) -> Option<&ImageSpec> {
+        self.images.iter().find(|image| {
+            image.env_type == env_type(docker)
+                && blender_version
+                    .map(|v| image.blender_version == v)
+                    .unwrap_or(true)
+        })
+    }
+}
+
 pub fn blender_deployment_spec(
     peer: Peer,
-    docker: bool,
+    image: &ImageSpec,
+    tags: Vec<String>,
 ) -> impl Future<Item = PeerSession, Error = gu_client::error::Error> + 'static {
-    if !docker {
+    if !image.is_docker() {
         future::Either::A(peer.new_session(CreateSession {
-            env_type: "hd".to_string(),
+            env_type: image.env_type.clone(),
             image: Image {
-                url: "http://52.31.143.91/images/x86_64/linux/gu-blender.hdi".to_string(),
-                hash: "SHA1:213fad4e020ded42e6a949f61cb660cb69bc9845".to_string(),
+                url: image.url.clone(),
+                hash: image.hash.clone(),
             },
             name: "".to_string(),
-            tags: vec!["gu:render".into(), "gu:blender".into()],
+            tags,
             note: None,
             options: (),
         }))
@@ -189,26 +375,23 @@ pub fn blender_deployment_spec(
 
         future::Either::B(
             peer.new_session(CreateSession::<CreateOptions> {
-                env_type: "docker".to_string(),
+                env_type: image.env_type.clone(),
                 image: Image {
-                    url: "prekucki/gu-render-blender".to_string(),
-                    hash: "sha256:53d11e6866835986b625e9fb07aa73b31dc667da39fe04f56da0ef06a50e0083"
-                        .to_string(),
+                    url: image.url.clone(),
+                    hash: image.hash.clone(),
                 },
                 name: "".to_string(),
-                tags: vec!["gu:render".into(), "gu:blender".into()],
+                tags,
                 note: None,
                 options: CreateOptions {
-                    volumes: vec![
-                        VolumeDef::BindRw {
-                            src: "resources".into(),
-                            target: "/golem/resources".into(),
-                        },
-                        VolumeDef::BindRw {
-                            src: "output".into(),
-                            target: "/golem/output".into(),
-                        },
-                    ],
+                    volumes: image
+                        .volumes
+                        .iter()
+                        .map(|v| VolumeDef::BindRw {
+                            src: v.src.clone(),
+                            target: v.target.clone(),
+                        })
+                        .collect(),
                     cmd: None,
                     net: None
                 },
@@ -217,7 +400,74 @@ pub fn blender_deployment_spec(
     }
 }
 
+pub fn destroy_deployment(deployment: PeerSession) -> impl Future<Item = (), Error = ()> {
+    deployment.delete().then(|r| {
+        match r {
+            Ok(_) => log::debug!("deployment destroyed"),
+            Err(e) => log::warn!("unable to destroy deployment: {}", e),
+        }
+        Ok(())
+    })
+}
+
+/// Destroys blender deployments left on session peers by previous run of the
+/// mediator node, except ones of tasks which are being restored.
+pub fn reap_orphaned_deployments(
+    hub_session: HubSession,
+    node_id: &str,
+    restored_tasks: HashSet<String>,
+) -> impl Future<Item = (), Error = gu_client::error::Error> {
+    let owner = owner_tag(node_id, hub_session.id());
+    let restored_tags: HashSet<String> = restored_tasks.iter().map(|t| task_tag(t)).collect();
+
+    reap_deployments(hub_session, move |tags| {
+        tags.iter().any(|t| t == &owner) && !tags.iter().any(|t| restored_tags.contains(t))
+    })
+}
+
+/// Destroys deployments of the task left by previous run of the mediator node;
+/// restored task does not take them over.
+pub fn reap_task_deployments(
+    hub_session: HubSession,
+    node_id: &str,
+    task_id: &str,
+) -> impl Future<Item = (), Error = gu_client::error::Error> {
+    let owner = owner_tag(node_id, hub_session.id());
+    let task = task_tag(task_id);
+
+    reap_deployments(hub_session, move |tags| {
+        tags.iter().any(|t| t == &owner) && tags.iter().any(|t| t == &task)
+    })
+}
+
+fn reap_deployments(
+    hub_session: HubSession,
+    is_orphan: impl Fn(&[String]) -> bool + 'static,
+) -> impl Future<Item = (), Error = gu_client::error::Error> {
+    hub_session.list_peers().and_then(move |peers| {
+        let orphans: Vec<PeerSession> = peers
+            .flat_map(|p| {
+                let node_id = p.node_id;
+                let peer = hub_session.peer(node_id);
+                p.sessions
+                    .into_iter()
+                    .filter(|s| is_orphan(&s.tags.iter().cloned().collect::<Vec<_>>()))
+                    .map(move |s| {
+                        log::info!("reaping orphaned deployment {} @ {:?}", s.id, node_id);
+                        peer.session(s.id)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        future::join_all(orphans.into_iter().map(destroy_deployment)).then(|_| Ok(()))
+    })
+}
+
 pub fn decode(extra_data: serde_json::Value) -> Result<BlenderSubtaskSpec, failure::Error> {
+    if let Some(output_format) = extra_data.get("output_format").and_then(|f| f.as_str()) {
+        let _: OutputFormat = output_format.parse()?;
+    }
     match serde_json::from_value(extra_data.clone()) {
         Ok(v) => return Ok(v),
         _ => (),
@@ -236,7 +486,7 @@ mod test {
         let b = OldBlenderTaskSpec {
             frames: vec![],
             outfilebasename: "".to_string(),
-            output_format: "".to_string(),
+            output_format: "PNG".to_string(),
             scene_file: None,
             script_src: r#"
 
@@ -261,4 +511,77 @@ bpy.context.scene.render.use_compositing = bool(False)
         eprintln!("v={:?}", b.into_spec().unwrap())
     }
 
+    #[test]
+    fn test_expected_output_file_names() {
+        let spec: BlenderSubtaskSpec = serde_json::from_value(serde_json::json!({
+            "crops": [
+                {"borders_x": [0.0, 1.0], "borders_y": [0.0, 0.5], "outfilebasename": "top_"},
+                {"borders_x": [0.0, 1.0], "borders_y": [0.5, 1.0], "outfilebasename": "bottom_"}
+            ],
+            "samples": 0,
+            "resolution": [320, 240],
+            "frames": [1, 2],
+            "scene_file": "scene.blend",
+            "output_format": "PNG"
+        }))
+        .unwrap();
+
+        assert_eq!(
+            spec.expected_output_file_names(),
+            vec!["top_0001.png", "bottom_0001.png", "top_0002.png", "bottom_0002.png"]
+        );
+    }
+
+    #[test]
+    fn test_output_format() {
+        assert_eq!("exr".parse::<OutputFormat>().unwrap(), OutputFormat::Exr);
+        assert_eq!("JPEG".parse::<OutputFormat>().unwrap().extension(), "jpg");
+
+        let spec = decode(serde_json::json!({
+            "crops": [{"borders_x": [0.0, 1.0], "borders_y": [0.0, 1.0], "outfilebasename": "out"}],
+            "samples": 0,
+            "resolution": [320, 240],
+            "frames": [1],
+            "scene_file": "scene.blend",
+            "output_format": "OPEN_EXR"
+        }))
+        .unwrap();
+        assert_eq!(spec.expected_output_file_names(), vec!["out0001.exr"]);
+        assert_eq!(
+            serde_json::to_value(&spec).unwrap()["output_format"],
+            serde_json::json!("OPEN_EXR")
+        );
+
+        let err = decode(serde_json::json!({
+            "frames": [1],
+            "outfilebasename": "out",
+            "output_format": "GIF",
+            "scene_file": null,
+            "script_src": ""
+        }))
+        .unwrap_err();
+        assert_eq!(err.to_string(), "unsupported output format: GIF");
+    }
+
+    #[test]
+    fn test_find_image() {
+        let catalogue = ImageCatalogue::default();
+
+        assert!(catalogue.find(true, None).unwrap().is_docker());
+        assert!(!catalogue.find(false, Some("2.79")).unwrap().is_docker());
+        assert!(catalogue.find(true, Some("2.80")).is_none());
+
+        let custom: ImageCatalogue = serde_json::from_str(
+            r#"{"images": [{"blenderVersion": "2.80", "envType": "docker", "url": "mirror/blender", "hash": "sha256:00"}]}"#,
+        )
+        .unwrap();
+        let merged = custom.merge(&catalogue);
+
+        assert_eq!(merged.find(true, None).unwrap().url, "mirror/blender");
+        assert_eq!(
+            merged.find(true, Some("2.79")).unwrap().url,
+            "prekucki/gu-render-blender"
+        );
+    }
+
 }
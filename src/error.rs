@@ -10,6 +10,15 @@ pub enum Error {
 
     #[fail(display = "{}", _0)]
     JsonErr(#[cause] serde_json::error::Error),
+
+    #[fail(display = "{}", _0)]
+    IoErr(#[cause] std::io::Error),
+
+    #[fail(display = "keystore: {}", _0)]
+    Keystore(String),
+
+    #[fail(display = "database: {}", _0)]
+    Database(String),
 }
 
 pub fn other(msg: &str) -> Error {
@@ -27,4 +36,10 @@ impl From<serde_json::Error> for Error {
     fn from(e: serde_json::Error) -> Self {
         Error::JsonErr(e)
     }
-}
\ No newline at end of file
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IoErr(e)
+    }
+}
@@ -1,15 +1,25 @@
-use super::task_worker::{DoResource, DoSubTask, DoSubtaskVerification, TaskWorker};
+use super::task_worker::{
+    DoResource, DoSubTask, DoSubtaskVerification, Drain, StopTask, TaskWorker,
+};
+use super::activator::SessionConfig;
+use super::acceptance::{self, AcceptancePolicy, Capacity, Rejection, TaskInfo};
+use super::backoff::Backoff;
+use super::keystore::NodeIdentity;
+use super::stats::SessionStats;
+use super::workman::{self, PeerHardware, SelectionStrategy};
+use super::{blender, keygen, model};
 use actix::prelude::*;
 /** Module responsible for signle HUB session.
 
 Traces given hub session.
 
 **/
-use futures::prelude::*;
-use serde_derive::*;
-use std::collections::HashMap;
+use diesel::SqliteConnection;
+use futures::{future, prelude::*};
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub struct Gateway {
     dav_url: String,
@@ -17,41 +27,132 @@ pub struct Gateway {
     api: Option<std::rc::Rc<dyn golem_gw_api::apis::DefaultApi>>,
     hub_session: Option<gu_client::r#async::HubSession>,
     session_id: Option<u64>,
+    standalone: bool,
     last_event_id: i64,
     tasks: HashMap<String, Addr<TaskWorker>>,
-    stats: StatsData,
-    account : String
+    account : String,
+    docker: bool,
+    max_peers_per_task: usize,
+    deadline_margin: Duration,
+    peer_selection: SelectionStrategy,
+    slots_per_peer: Option<usize>,
+    images: Arc<blender::ImageCatalogue>,
+    identity: NodeIdentity,
+    subscription_id: Option<String>,
+    /// Environment error shown in session config, cleared by next successful deployment.
+    last_error: Option<String>,
+    paused: bool,
+    subscribed: bool,
+    draining: bool,
+    status: &'static str,
+    backoff: Backoff,
+    peers: Vec<gu_client::NodeId>,
+    peer_hardware: HashMap<gu_client::NodeId, PeerHardware>,
+    subscription_params: SubscriptionParams,
+    acceptance: Vec<Box<dyn AcceptancePolicy>>,
+    db: SqliteConnection,
 }
 
-pub struct Stats;
+/// Name the node registers under, unless configured.
+pub const DEFAULT_SESSION_NAME: &str = "gu-mediator blendering";
 
-#[derive(Serialize, Deserialize, Clone, Default)]
-#[serde(rename_all = "camelCase")]
-pub struct StatsData {
-    pub tasks: u64,
-    pub subtasks: u64,
-    pub subtasks_done: u64,
-    pub fails: u64,
+/// Subscription parameters configured for the session.
+struct SubscriptionParams {
+    min_price: Option<f64>,
+    max_cpu_cores: Option<u32>,
+    max_memory: Option<u64>,
+    max_disk: Option<u64>,
+    performance: Option<f32>,
+    name: Option<String>,
 }
 
+const DEFAULT_MIN_PRICE: f64 = 1.0;
+const DEFAULT_MAX_CPU_CORES: u32 = 6;
+const DEFAULT_MAX_MEMORY: u64 = 3 * 1024 * 1024 * 512;
+const DEFAULT_MAX_DISK: u64 = 3 * 1024 * 1024 * 512;
+const DEFAULT_PERFORMANCE: f32 = 1000.0;
+const PEER_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_POLL_DELAY: Duration = Duration::from_secs(60);
+/// Consecutive polling failures after which subscription is renewed.
+const RECONNECT_AFTER: u32 = 5;
+
+/// Reads session statistics. Stats are computed from recorded events, so reading them has no side effects.
+pub struct Stats;
+
 impl Message for Stats {
-    type Result = Result<StatsData, super::error::Error>;
+    type Result = Result<SessionStats, super::error::Error>;
 }
 
 impl Gateway {
 
-    pub fn new(session_id: Option<u64>, dav_url: String, base_url: String, account : String) -> Gateway {
-        Gateway {
-            dav_url,
-            base_url,
+    /// Gateway of a standalone session adds peers to the hub session itself.
+    pub fn new(
+        session_id: u64,
+        standalone: bool,
+        config: SessionConfig,
+        identity: NodeIdentity,
+        images: Arc<blender::ImageCatalogue>,
+    ) -> Result<Gateway, super::error::Error> {
+        let db = model::establish_connection()?;
+        let paused = config.is_paused();
+        let min_time_left = config.min_time_left();
+        let images = match config.images {
+            Some(session_images) => Arc::new(session_images.merge(&images)),
+            None => images,
+        };
+
+        let mut acceptance: Vec<Box<dyn AcceptancePolicy>> = vec![
+            Box::new(acceptance::DeadlinePolicy {
+                min_time_left,
+            }),
+            Box::new(acceptance::MemoryFitPolicy),
+        ];
+        if let Some(min_price) = config.min_price {
+            acceptance.push(Box::new(acceptance::MinPricePolicy { min_price }));
+        }
+        if let Some(max_tasks) = config.max_tasks {
+            acceptance.push(Box::new(acceptance::ConcurrencyPolicy { max_tasks }));
+        }
+
+        Ok(Gateway {
+            dav_url: config.dav_url,
+            base_url: config.gw_url,
             api: None,
             last_event_id: -1,
-            session_id,
+            session_id: Some(session_id),
+            standalone,
             tasks: HashMap::new(),
             hub_session: None,
-            stats: StatsData::default(),
-            account
-        }
+            account: config.account,
+            docker: config.docker,
+            max_peers_per_task: config.max_peers_per_task.max(1),
+            deadline_margin: Duration::from_secs(config.deadline_margin_secs),
+            peer_selection: config.peer_selection,
+            slots_per_peer: config.slots_per_peer.map(|slots| slots.max(1)),
+            images,
+            identity,
+            subscription_id: None,
+            last_error: None,
+            paused,
+            subscribed: false,
+            draining: false,
+            status: "",
+            backoff: Backoff::new(POLL_INTERVAL, MAX_POLL_DELAY),
+            peers: config.peers,
+            peer_hardware: HashMap::new(),
+            subscription_params: SubscriptionParams {
+                min_price: config.min_price,
+                max_cpu_cores: config.max_cpu_cores,
+                max_memory: config.max_memory,
+                max_disk: config.max_disk,
+                performance: config.performance,
+                name: config.name,
+            },
+            acceptance,
+            db,
+        })
     }
 
     fn api(&self) -> &golem_gw_api::apis::DefaultApi {
@@ -59,26 +160,62 @@ impl Gateway {
     }
 
     fn set_status(&mut self, msg: &str, ctx: &mut <Self as Actor>::Context) {
+        self.set_config_entry("status", msg, ctx)
+    }
+
+    fn set_config_entry(&mut self, key: &str, msg: &str, ctx: &mut <Self as Actor>::Context) {
+        ctx.spawn(self.update_config_entry(key, Some(msg)).into_actor(self));
+    }
+
+    fn clear_config_entry(&mut self, key: &str, ctx: &mut <Self as Actor>::Context) {
+        ctx.spawn(self.update_config_entry(key, None).into_actor(self));
+    }
+
+    /// Sets entry of session config; `None` removes it.
+    fn update_config_entry(
+        &self,
+        key: &str,
+        msg: Option<&str>,
+    ) -> Box<dyn Future<Item = (), Error = ()>> {
+        self.update_config_value(key, msg.map(|msg| serde_json::Value::String(msg.to_owned())))
+    }
+
+    fn update_config_value(
+        &self,
+        key: &str,
+        value: Option<serde_json::Value>,
+    ) -> Box<dyn Future<Item = (), Error = ()>> {
         let hub_session = match &self.hub_session {
             Some(s) => s.clone(),
-            None => return,
+            None => return Box::new(future::ok(())),
         };
 
         let config = hub_session.config();
-        let status = match serde_json::to_value(msg) {
-            Ok(s) => s,
-            Err(e) => return,
-        };
-        ctx.spawn(
+        let key = key.to_owned();
+        Box::new(
             config
                 .and_then(move |mut c: gu_client::model::session::Metadata| {
-                    c.entry.insert("status".to_owned(), status);
+                    match value {
+                        Some(value) => {
+                            c.entry.insert(key, value);
+                        }
+                        None => {
+                            c.entry.remove(&key);
+                        }
+                    }
                     hub_session.set_config(c)
                 })
                 .map_err(|e| log::error!("update config {}", e))
-                .and_then(|_| Ok(()))
-                .into_actor(self),
-        );
+                .and_then(|_| Ok(())),
+        )
+    }
+
+    /// Stores connection status in session config, skipping unchanged ones.
+    fn update_status(&mut self, status: &'static str, ctx: &mut <Self as Actor>::Context) {
+        if self.status != status {
+            self.status = status;
+            self.set_status(status, ctx)
+        }
     }
 
     fn init_api(&mut self) -> &golem_gw_api::apis::DefaultApi {
@@ -96,37 +233,141 @@ impl Gateway {
     }
 
     fn name(&self) -> &str {
-        "gu-mediator blendering"
+        self.subscription_params
+            .name
+            .as_ref()
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_SESSION_NAME)
     }
 
     fn node_id(&self) -> &str {
-        "0xb2bbb75241939e50b5ba6f698415bbb5ca54610d"
+        &self.identity.node_id
     }
 
     fn eth_public_key(&self) -> &str {
-        "bf1abe57ba441ba1b3a6ee433cf1fd6028fec6061db84272a20beb2e760314162ad00451cd84584eaed4f1fc38b394e35c36d3e54925ac13e3a751fae3a66e0e"
+        &self.identity.eth_public_key
     }
 
     fn task_type(&self) -> &str {
         "Blender"
     }
 
+    /// Subscription built from session config; parameters not configured are
+    /// derived from the largest peer of the session.
+    fn subscription(&self) -> golem_gw_api::models::Subscription {
+        let params = &self.subscription_params;
+        let hw = PeerHardware::max(self.peer_hardware.values()).unwrap_or_default();
+        let derived = |v: u64| if v > 0 { Some(v) } else { None };
+
+        let max_cpu_cores = params
+            .max_cpu_cores
+            .or_else(|| derived(hw.cores.into()).map(|cores| cores as u32))
+            .unwrap_or(DEFAULT_MAX_CPU_CORES);
+        let max_memory = params
+            .max_memory
+            .or_else(|| derived(hw.memory))
+            .unwrap_or(DEFAULT_MAX_MEMORY);
+        let max_disk = params
+            .max_disk
+            .or_else(|| derived(hw.disk))
+            .unwrap_or(DEFAULT_MAX_DISK);
+        let performance = params
+            .performance
+            .or_else(|| derived(hw.cores.into()).map(|_| hw.performance()))
+            .unwrap_or(DEFAULT_PERFORMANCE);
+
+        golem_gw_api::models::Subscription::new(
+            params.min_price.unwrap_or(DEFAULT_MIN_PRICE),
+            max_cpu_cores as _,
+            max_memory as _,
+            max_disk as _,
+        )
+        .with_name(self.name().into())
+        .with_performance(performance)
+        .with_eth_addr(self.account.clone())
+        .with_eth_pub_key(self.eth_public_key().into())
+    }
+
     fn new_subscription(&self) -> impl Future<Item = (), Error = failure::Error> {
         self.api()
-            .subscribe(
-                self.node_id(),
-                self.task_type(),
-                golem_gw_api::models::Subscription::new(
-                    1f64,
-                    6,
-                    3 * 1024 * 1024 * 512,
-                    3 * 1024 * 1024 * 512,
+            .subscribe(self.node_id(), self.task_type(), self.subscription())
+            .and_then(|s| Ok(log::info!("status: {}", serde_json::to_string_pretty(&s)?)))
+            .from_err()
+    }
+
+    /// Reads hardware of session peers; resolves to `true` when the peer set changed.
+    fn update_peers(&self) -> Box<dyn ActorFuture<Actor = Self, Item = bool, Error = ()>> {
+        let session_id = match self.session_id {
+            Some(session_id) => session_id,
+            None => return Box::new(fut::ok(false)),
+        };
+
+        Box::new(
+            workman::session_hardware(session_id)
+                .into_actor(self)
+                .map(|hardware, act: &mut Gateway, _| {
+                    let changed = hardware.len() != act.peer_hardware.len()
+                        || hardware.keys().any(|k| !act.peer_hardware.contains_key(k));
+                    if changed {
+                        log::info!("session peers changed, {} peers", hardware.len());
+                    }
+                    act.peer_hardware = hardware;
+                    changed
+                })
+                .map_err(|e, _, _| log::warn!("unable to read session peers: {}", e)),
+        )
+    }
+
+    /// Updates subscription when peers were added to or removed from the session.
+    fn check_peers(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if !self.subscribed || self.draining {
+            return;
+        }
+
+        ctx.spawn(self.update_peers().and_then(|changed, act: &mut Gateway, _| {
+            if changed {
+                fut::Either::A(
+                    act.new_subscription()
+                        .into_actor(act)
+                        .map_err(|e, _, _| log::error!("unable to update subscription: {}", e)),
                 )
-                .with_name(self.name().into())
-                .with_performance(1000f32)
-                .with_eth_addr(self.account.clone()),
+            } else {
+                fut::Either::B(fut::ok(()))
+            }
+        }));
+    }
+
+    /// Adds configured peers (or all hub peers when none are configured) to
+    /// the hub session created by the gateway itself.
+    fn add_peers(
+        &self,
+        hub_session: gu_client::r#async::HubSession,
+    ) -> impl Future<Item = (), Error = ()> {
+        let peers = if self.peers.is_empty() {
+            future::Either::A(
+                gu_client::r#async::HubConnection::default()
+                    .list_peers()
+                    .map(|peers| peers.map(|p| p.node_id).collect::<Vec<_>>()),
             )
-            .and_then(|s| Ok(log::info!("status: {}", serde_json::to_string_pretty(&s)?)))
+        } else {
+            future::Either::B(future::ok(self.peers.clone()))
+        };
+
+        peers
+            .and_then(move |peers| {
+                log::info!("adding {} peers to session {}", peers.len(), hub_session.id());
+                hub_session.add_peers(peers)
+            })
+            .map_err(|e| log::error!("unable to add peers to session: {}", e))
+            .map(|_| ())
+    }
+
+    fn unsubscribe(&self) -> impl Future<Item = (), Error = failure::Error> {
+        let node_id = self.node_id().to_owned();
+
+        self.api()
+            .unsubscribe(self.node_id(), self.task_type())
+            .and_then(move |_| Ok(log::info!("unsubscribed {}", node_id)))
             .from_err()
     }
 
@@ -146,34 +387,293 @@ impl Gateway {
         );
         if self.last_event_id < event_id {
             self.last_event_id = event_id;
+            self.store_cursor();
+        }
+    }
+
+    fn store_cursor(&self) {
+        if let Some(subscription_id) = &self.subscription_id {
+            if let Err(e) = model::update_last_event_id(&self.db, subscription_id, self.last_event_id)
+            {
+                log::error!("unable to store event cursor {}: {}", self.last_event_id, e);
+            }
+        }
+    }
+
+    /// Loads (or creates) subscription of the node identity, restores event cursor
+    /// and workers for tasks taken before restart. Tasks taken in another hub
+    /// session are left behind.
+    fn restore_state(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let session_id = match self.session_id {
+            Some(session_id) => session_id as i32,
+            None => return,
+        };
+        let subscription_id = keygen::subscription_id(self.node_id(), self.task_type());
+
+        let subscription = match model::find_subscription(&self.db, &subscription_id) {
+            Ok(Some(subscription)) => subscription,
+            Ok(None) => {
+                let subscription = model::Subscription {
+                    hub_id: hub_id(),
+                    session_id,
+                    subscription_id,
+                    last_event_id: -1,
+                };
+                if let Err(e) = model::insert_subscription(&self.db, &subscription) {
+                    log::error!("unable to store subscription for session {}: {}", session_id, e);
+                }
+                subscription
+            }
+            Err(e) => {
+                log::error!("unable to load subscription for session {}: {}", session_id, e);
+                return;
+            }
+        };
+
+        log::info!(
+            "subscription {} restored, last event: {}",
+            subscription.subscription_id,
+            subscription.last_event_id
+        );
+        self.last_event_id = subscription.last_event_id;
+        self.subscription_id = Some(subscription.subscription_id.clone());
+
+        if subscription.session_id == session_id {
+            self.restore_tasks(ctx);
+        } else {
+            log::info!(
+                "subscription {} taken over from session {}, its tasks are not restored",
+                subscription.subscription_id,
+                subscription.session_id
+            );
+            if let Err(e) = model::update_subscription_session(
+                &self.db,
+                &subscription.subscription_id,
+                session_id,
+            ) {
+                log::error!("unable to store subscription for session {}: {}", session_id, e);
+            }
+        }
+    }
+
+    /// Tasks of the session subscription which will be restored on start.
+    fn restored_task_ids(&self) -> HashSet<String> {
+        let subscription_id = keygen::subscription_id(self.node_id(), self.task_type());
+        let now = chrono::Utc::now().naive_utc();
+
+        match model::find_subscription(&self.db, &subscription_id) {
+            Ok(Some(ref subscription))
+                if Some(subscription.session_id as u64) == self.session_id => {}
+            _ => return HashSet::new(),
+        }
+
+        match model::active_tasks(&self.db, &subscription_id, now) {
+            Ok(tasks) => tasks.into_iter().map(|t| t.task_id).collect(),
+            Err(e) => {
+                log::error!("unable to load tasks of {}: {}", subscription_id, e);
+                HashSet::new()
+            }
+        }
+    }
+
+    fn restore_tasks(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let subscription_id = match &self.subscription_id {
+            Some(subscription_id) => subscription_id.clone(),
+            None => return,
+        };
+        let now = chrono::Utc::now().naive_utc();
+
+        let tasks = match model::active_tasks(&self.db, &subscription_id, now) {
+            Ok(tasks) => tasks,
+            Err(e) => {
+                log::error!("unable to load tasks of {}: {}", subscription_id, e);
+                return;
+            }
+        };
+
+        for t in tasks {
+            let task: golem_gw_api::models::Task = match model::last_event_of_type(
+                &self.db,
+                &subscription_id,
+                &t.task_id,
+                "task",
+            ) {
+                Ok(Some(model::SubscriptionEvent {
+                    event_desc: Some(desc),
+                    ..
+                })) => match serde_json::from_str(&desc) {
+                    Ok(task) => task,
+                    Err(e) => {
+                        log::warn!("invalid task {} stored: {}", t.task_id, e);
+                        continue;
+                    }
+                },
+                Ok(_) => {
+                    log::warn!("no task event stored for {}", t.task_id);
+                    continue;
+                }
+                Err(e) => {
+                    log::error!("unable to load task {}: {}", t.task_id, e);
+                    continue;
+                }
+            };
+            log::info!("restoring worker for task {}", t.task_id);
+            self.start_worker(&task, ctx);
         }
     }
 
-    fn process_event(&mut self, ev: &golem_gw_api::models::Event) {
+    fn start_worker(
+        &mut self,
+        task: &golem_gw_api::models::Task,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let worker = TaskWorker::new(
+            self.dav_url.clone(),
+            self.api.as_ref().unwrap(),
+            self.hub_session.clone().unwrap(),
+            self.node_id(),
+            task,
+            self.docker,
+            self.max_peers_per_task,
+            self.deadline_margin,
+            self.peer_selection,
+            self.slots_per_peer,
+            self.images.clone(),
+            ctx.address(),
+        )
+        .start();
+        self.tasks.insert(task.task_id().to_owned(), worker);
+    }
+
+    fn check_acceptance(&self, task: &golem_gw_api::models::Task) -> Result<(), Rejection> {
+        let capacity = Capacity {
+            now: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            running_tasks: self.tasks.values().filter(|w| w.connected()).count(),
+            peers: self.peer_hardware.values().cloned().collect(),
+        };
+
+        self.acceptance.check(&TaskInfo::from_task(task), &capacity)
+    }
+
+    fn store_task(&self, task: &golem_gw_api::models::Task) {
+        let subscription_id = match &self.subscription_id {
+            Some(subscription_id) => subscription_id.clone(),
+            None => return,
+        };
+
+        let info = TaskInfo::from_task(task);
+        let task_row = model::SubscriptionTask {
+            subscription_id,
+            task_id: info.task_id,
+            deadline: Some(chrono::NaiveDateTime::from_timestamp(info.deadline as i64, 0)),
+            resource_size: info.resource_size.map(|size| size as i32),
+            estimated_memory: info.estimated_memory.map(|memory| memory as i64),
+            max_price_gnt: info.price,
+        };
+        if let Err(e) = model::insert_task(&self.db, &task_row) {
+            log::error!("unable to store task {}: {}", task_row.task_id, e);
+        }
+    }
+
+    fn store_subtask(&self, subtask: &golem_gw_api::models::Subtask) {
+        let subscription_id = match &self.subscription_id {
+            Some(subscription_id) => subscription_id.clone(),
+            None => return,
+        };
+
+        let subtask_row = model::SubscriptionSubtask {
+            subscription_id,
+            task_id: subtask.task_id().to_owned(),
+            subtask_id: subtask.subtask_id().to_owned(),
+            price_gnt: None,
+            deadline: Some(chrono::NaiveDateTime::from_timestamp(
+                (*subtask.deadline()) as i64,
+                0,
+            )),
+            status: "pending".into(),
+        };
+        if let Err(e) = model::insert_subtask(&self.db, &subtask_row) {
+            log::error!("unable to store subtask {}: {}", subtask_row.subtask_id, e);
+        }
+    }
+
+    fn record_event(
+        &self,
+        event_type: &str,
+        task_id: &str,
+        subtask_id: Option<&String>,
+        event_desc: Option<String>,
+    ) {
+        let subscription_id = match &self.subscription_id {
+            Some(subscription_id) => subscription_id.clone(),
+            None => return,
+        };
+
+        let event = model::NewSubscriptionEvent {
+            subscription_id,
+            task_id: task_id.to_owned(),
+            subtask_id: subtask_id.cloned(),
+            event_type: event_type.to_owned(),
+            event_desc,
+        };
+        if let Err(e) = model::insert_event(&self.db, &event) {
+            log::error!("unable to record {} event for {}: {}", event_type, task_id, e);
+        }
+    }
+
+    fn process_event(
+        &mut self,
+        ev: &golem_gw_api::models::Event,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
         if let Some(task) = ev.task() {
-            let worker = TaskWorker::new(
-                self.dav_url.clone(),
-                self.api.as_ref().unwrap(),
-                self.hub_session.clone().unwrap(),
-                self.node_id(),
-                task,
-            )
-            .start();
-            self.stats.tasks += 1;
-            self.tasks.insert(task.task_id().to_owned(), worker);
+            let taken = self
+                .tasks
+                .get(task.task_id())
+                .map(|worker| worker.connected())
+                .unwrap_or(false);
+            if taken {
+                log::warn!("task {} already taken", task.task_id());
+            } else if self.paused || self.draining {
+                log::info!("gateway paused, task {} skipped", task.task_id());
+            } else if let Err(rejection) = self.check_acceptance(task) {
+                log::info!("task {} rejected: {}", task.task_id(), rejection);
+                self.record_event("task_rejected", task.task_id(), None, Some(rejection.0));
+            } else {
+                self.store_task(task);
+                self.record_event("task", task.task_id(), None, serde_json::to_string(task).ok());
+                self.start_worker(task, ctx);
+            }
         } else if let Some(subtask) = ev.subtask() {
+            self.record_event("subtask", subtask.task_id(), Some(subtask.subtask_id()), None);
+            self.store_subtask(subtask);
             if let Some(worker) = self.tasks.get(subtask.task_id()) {
                 worker.do_send(DoSubTask(subtask.clone()))
             } else {
                 log::warn!("no worker for: {}", subtask.task_id());
             }
         } else if let Some(resource) = ev.resource() {
+            self.record_event(
+                "resource",
+                resource.res_id(),
+                Some(resource.subtask_id()),
+                Some(resource.path().to_owned()),
+            );
             if let Some(worker) = self.tasks.get(resource.res_id()) {
                 worker.do_send(DoResource(resource.clone()))
             } else {
                 log::warn!("no worker for: {}", resource.res_id());
             }
         } else if let Some(subtask_verification) = ev.subtask_verification() {
+            self.record_event(
+                "subtask_verification",
+                subtask_verification.task_id(),
+                Some(subtask_verification.subtask_id()),
+                Some(subtask_verification.verification_result().to_owned()),
+            );
             if let Some(worker) = self.tasks.get(subtask_verification.task_id()) {
                 worker.do_send(DoSubtaskVerification(subtask_verification.clone()))
             } else {
@@ -186,24 +686,87 @@ impl Gateway {
         self.ack_event(ev.event_id());
     }
 
-    fn pump_events(
-        &self,
-        ctx: &mut <Self as Actor>::Context,
-    ) -> impl ActorFuture<Actor = Self, Item = (), Error = ()> {
-        ctx.run_interval(Duration::from_secs(1), |act, ctx| {
-            let f = act
-                .poll_events()
-                .map_err(|e| log::error!("polling events failed: {}", e))
-                .into_actor(act)
-                .and_then(|events, act, _| {
-                    for ev in events {
-                        act.process_event(&ev)
+    /// Polls gateway for events. Polling is rescheduled after each attempt, with
+    /// backoff on failures; subscription is renewed when it may have been lost.
+    fn pump_events(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if self.draining {
+            return;
+        }
+
+        let subscribe = if self.subscribed {
+            fut::Either::A(fut::ok(()))
+        } else {
+            fut::Either::B(
+                self.update_peers()
+                    .then(|_, act: &mut Gateway, _| act.new_subscription().into_actor(act))
+                    .map(|_, act: &mut Gateway, ctx| {
+                        act.subscribed = true;
+                        act.update_status("working", ctx);
+                    }),
+            )
+        };
+
+        let f = subscribe
+            .and_then(|_, act: &mut Gateway, _| act.poll_events().into_actor(act))
+            .then(|r, act: &mut Gateway, ctx| {
+                let delay = match r {
+                    Ok(events) => {
+                        act.connection_restored(ctx);
+                        for ev in events {
+                            act.process_event(&ev, ctx)
+                        }
+                        POLL_INTERVAL
                     }
-                    fut::ok(())
-                });
-            ctx.spawn(f);
-        });
-        fut::ok(())
+                    Err(e) => act.connection_failed(e, ctx),
+                };
+                ctx.run_later(delay, |act, ctx| act.pump_events(ctx));
+                fut::ok(())
+            });
+        ctx.spawn(f);
+    }
+
+    fn connection_restored(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if self.backoff.failures() > 0 {
+            log::info!("gateway connection restored after {} failures", self.backoff.failures());
+            self.backoff.reset();
+            self.update_status("working", ctx);
+        }
+    }
+
+    /// Returns delay before next poll.
+    fn connection_failed(
+        &mut self,
+        e: failure::Error,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Duration {
+        let delay = self.backoff.fail();
+        let failures = self.backoff.failures();
+        log::error!(
+            "polling events failed ({} in a row), next try in {:?}: {}",
+            failures,
+            delay,
+            e
+        );
+
+        if is_subscription_lost(&e) || failures >= RECONNECT_AFTER {
+            self.subscribed = false;
+        }
+        if failures >= RECONNECT_AFTER {
+            self.update_status("reconnecting", ctx);
+        } else {
+            self.update_status("degraded", ctx);
+        }
+        delay
+    }
+}
+
+/// Gateway answers 404 for nodes it has no subscription of, e.g. after its restart.
+fn is_subscription_lost(e: &failure::Error) -> bool {
+    use golem_gw_api::apis::Error as ApiError;
+
+    match e.downcast_ref::<ApiError<serde_json::Value>>() {
+        Some(ApiError::ApiError(api_error)) => api_error.code == hyper::StatusCode::NOT_FOUND,
+        _ => false,
     }
 }
 
@@ -214,102 +777,315 @@ impl Actor for Gateway {
         let _api = self.init_api();
 
         let hub_connection = gu_client::r#async::HubConnection::default();
+        let hub_session = hub_connection.hub_session(self.session_id.unwrap());
+        self.hub_session = Some(hub_session.clone());
 
-        if let Some(session_id) = self.session_id {
-            self.hub_session = Some(hub_connection.hub_session(session_id));
+        let add_peers = if self.standalone {
+            future::Either::A(self.add_peers(hub_session.clone()))
         } else {
-            let create_hub_session = hub_connection
-                .new_session(gu_client::model::session::HubSessionSpec {
-                    expires: None,
-                    allocation: gu_client::model::session::AllocationMode::AUTO,
-                    name: Some(self.name().into()),
-                    tags: std::collections::BTreeSet::new(),
-                })
+            future::Either::B(future::ok(()))
+        };
+
+        let node_id = self.node_id().to_owned();
+        let restored_tasks = self.restored_task_ids();
+        let f = add_peers
+            .then(move |_| blender::reap_orphaned_deployments(hub_session, &node_id, restored_tasks))
+            .then(|r| {
+                if let Err(e) = r {
+                    log::warn!("unable to reap orphaned deployments: {}", e);
+                }
+                Ok(())
+            })
+            .into_actor(self)
+            .map(|_, act: &mut Gateway, ctx| {
+                act.restore_state(ctx);
+                act.pump_events(ctx)
+            });
+        ctx.spawn(f);
+        ctx.run_interval(PEER_CHECK_INTERVAL, |act, ctx| act.check_peers(ctx));
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        for (_, worker) in self.tasks.drain() {
+            worker.do_send(StopTask);
+        }
+    }
+}
+
+/// Gateway does not know the task anymore, so its worker stopped.
+pub struct TaskFinished {
+    pub task_id: String,
+}
+
+impl Message for TaskFinished {
+    type Result = ();
+}
+
+impl Handler<TaskFinished> for Gateway {
+    type Result = ();
+
+    fn handle(&mut self, msg: TaskFinished, _ctx: &mut Self::Context) -> Self::Result {
+        // finished tasks are not restored on restart
+        self.record_event("task_finished", &msg.task_id, None, None);
+    }
+}
+
+/// Task worker stopped; its task no longer counts as running.
+pub struct WorkerStopped {
+    pub task_id: String,
+}
+
+impl Message for WorkerStopped {
+    type Result = ();
+}
+
+impl Handler<WorkerStopped> for Gateway {
+    type Result = ();
+
+    fn handle(&mut self, msg: WorkerStopped, _ctx: &mut Self::Context) -> Self::Result {
+        self.tasks.remove(&msg.task_id);
+    }
+}
+
+/// Marks the session stopped, then drains it like `DrainSession`: task workers
+/// get the grace period to finish subtasks before the gateway stops.
+pub struct Shutdown {
+    pub grace_period: Duration,
+}
+
+impl Message for Shutdown {
+    type Result = Result<(), super::error::Error>;
+}
+
+impl Handler<Shutdown> for Gateway {
+    type Result = ActorResponse<Self, (), super::error::Error>;
+
+    fn handle(&mut self, msg: Shutdown, _ctx: &mut Self::Context) -> Self::Result {
+        log::info!("stopping gateway of session {:?}", self.session_id);
+
+        ActorResponse::r#async(
+            self.update_config_entry("status", Some("stopped"))
+                .then(|_| Ok::<(), super::error::Error>(()))
                 .into_actor(self)
-                .map_err(|e, act, ctx| {
-                    log::error!("failed to create hub session {:?}: {}", act.hub_session, e);
-                    ctx.stop()
+                .and_then(move |_, act: &mut Gateway, _| act.drain(msg.grace_period)),
+        )
+    }
+}
+
+/// Stops polling, drains task workers within the grace period and unsubscribes.
+/// Session status is kept, so the gateway is restored on next start.
+pub struct DrainSession {
+    pub grace_period: Duration,
+}
+
+impl Message for DrainSession {
+    type Result = Result<(), super::error::Error>;
+}
+
+impl Handler<DrainSession> for Gateway {
+    type Result = ActorResponse<Self, (), super::error::Error>;
+
+    fn handle(&mut self, msg: DrainSession, _ctx: &mut Self::Context) -> Self::Result {
+        ActorResponse::r#async(self.drain(msg.grace_period))
+    }
+}
+
+impl Gateway {
+    fn drain(
+        &mut self,
+        grace_period: Duration,
+    ) -> impl ActorFuture<Actor = Gateway, Item = (), Error = super::error::Error> {
+        log::info!("draining gateway of session {:?}", self.session_id);
+        self.draining = true;
+
+        let workers: Vec<_> = self
+            .tasks
+            .drain()
+            .map(move |(task_id, worker)| {
+                worker.send(Drain { grace_period }).flatten().then(move |r| {
+                    if let Err(e) = r {
+                        log::warn!("unable to drain worker of task {}: {}", task_id, e);
+                    }
+                    Ok::<(), super::error::Error>(())
                 })
-                .and_then(|h, mut act, _| {
-                    let hub_session: gu_client::r#async::HubSession = h.into_inner().unwrap();
-                    act.session_id = Some(hub_session.id());
-                    act.hub_session = Some(hub_session);
-                    fut::ok(())
-                });
-
-            ctx.spawn(create_hub_session);
-        }
+            })
+            .collect();
 
-        let f = self
-            .new_subscription()
+        future::join_all(workers)
             .into_actor(self)
-            .map_err(|e, act: &mut Gateway, ctx| {
-                log::error!("Unable to update subscription: {}", e);
-                act.set_status(&format!("error: {}", e), ctx);
+            .and_then(|_, act: &mut Gateway, _| {
+                act.unsubscribe()
+                    .then(|r| {
+                        if let Err(e) = r {
+                            log::warn!("unable to unsubscribe: {}", e);
+                        }
+                        Ok(())
+                    })
+                    .into_actor(act)
+            })
+            .map(|_, act: &mut Gateway, ctx| {
+                act.store_cursor();
                 ctx.stop()
             })
-            .and_then(|_, act, ctx| fut::ok(act.set_status("working", ctx)));
-        ctx.spawn(f.and_then(|_, act, ctx| act.pump_events(ctx)));
     }
 }
 
-impl Handler<Stats> for Gateway {
-    type Result = ActorResponse<Self, StatsData, super::error::Error>;
+/// Stops taking new tasks; subtasks of already taken tasks are still computed.
+pub struct Pause;
 
-    fn handle(&mut self, msg: Stats, ctx: &mut Self::Context) -> Self::Result {
+impl Message for Pause {
+    type Result = ();
+}
 
-        let tasks = self.tasks.len();
+impl Handler<Pause> for Gateway {
+    type Result = ();
 
-        let e: Vec<String> = self
-            .tasks
-            .iter()
-            .filter_map(|(k, v)| {
-                if !v.connected() {
-                    Some(k.to_owned())
-                } else {
-                    None
-                }
-            })
-            .collect();
+    fn handle(&mut self, _msg: Pause, ctx: &mut Self::Context) -> Self::Result {
+        log::info!("pausing gateway of session {:?}", self.session_id);
+        self.paused = true;
+        // kept apart from status, which follows the gateway connection
+        ctx.spawn(
+            self.update_config_value("paused", Some(serde_json::Value::Bool(true)))
+                .into_actor(self),
+        );
+    }
+}
+
+pub struct Resume;
+
+impl Message for Resume {
+    type Result = ();
+}
+
+impl Handler<Resume> for Gateway {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Resume, ctx: &mut Self::Context) -> Self::Result {
+        log::info!("resuming gateway of session {:?}", self.session_id);
+        self.paused = false;
+        ctx.spawn(self.update_config_value("paused", None).into_actor(self));
+    }
+}
+
+/// Selected environment could not be deployed on a peer.
+pub struct EnvironmentUnavailable {
+    pub peer_id: Option<gu_client::NodeId>,
+    pub docker: bool,
+    pub error: String,
+}
+
+impl Message for EnvironmentUnavailable {
+    type Result = ();
+}
+
+impl Handler<EnvironmentUnavailable> for Gateway {
+    type Result = ();
+
+    fn handle(&mut self, msg: EnvironmentUnavailable, ctx: &mut Self::Context) -> Self::Result {
+        let error = format!(
+            "{} environment not available on peer {:?}: {}",
+            blender::env_type(msg.docker),
+            msg.peer_id,
+            msg.error
+        );
+        log::error!("{}", error);
+        self.set_config_entry("lastError", &error, ctx);
+        self.last_error = Some(error);
+    }
+}
+
+/// Blender was deployed on a peer, so the environment works again.
+pub struct EnvironmentAvailable;
+
+impl Message for EnvironmentAvailable {
+    type Result = ();
+}
 
-        for k in e {
-            self.tasks.remove(&k);
+impl Handler<EnvironmentAvailable> for Gateway {
+    type Result = ();
+
+    fn handle(&mut self, _msg: EnvironmentAvailable, ctx: &mut Self::Context) -> Self::Result {
+        if let Some(error) = self.last_error.take() {
+            log::info!("environment available again, clearing: {}", error);
+            self.clear_config_entry("lastError", ctx);
         }
+    }
+}
 
-        let init = self.stats.clone();
+/// Failed subtask was reported to the gateway.
+pub struct SubtaskFailed {
+    pub task_id: String,
+    pub subtask_id: String,
+    pub reason: String,
+}
 
-        ActorResponse::r#async(
-            futures::future::join_all(
-                self.tasks
-                    .values()
-                    .map(|t| {
-                        t.send(Stats).flatten().then(|r| match r {
-                            Ok(r) => Ok(r),
-                            Err(e) => {
-                                log::warn!("get stats err: {}", e);
-                                Ok(StatsData::default())
-                            }
-                        })
-                    })
-                    .collect::<Vec<_>>(),
-            )
-            .and_then(move |r: Vec<StatsData>| {
-                let agg = r.into_iter().fold(init, |a, r| StatsData {
-                    tasks: a.tasks + r.tasks,
-                    subtasks_done: a.subtasks_done + r.subtasks_done,
-                    subtasks: a.subtasks + r.subtasks,
-                    fails: a.fails + r.fails,
-                });
-
-                Ok(agg)
-            })
-            .into_actor(self)
-            .and_then(|r, mut act, _| {
-                act.stats = r.clone();
-                fut::ok(r)
-            }),
-        )
+impl Message for SubtaskFailed {
+    type Result = ();
+}
+
+/// Subtask was aborted by the worker watchdog before its deadline.
+pub struct SubtaskTimedOut {
+    pub task_id: String,
+    pub subtask_id: String,
+}
+
+impl Message for SubtaskTimedOut {
+    type Result = ();
+}
+
+impl Handler<SubtaskTimedOut> for Gateway {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubtaskTimedOut, _ctx: &mut Self::Context) -> Self::Result {
+        self.record_event("subtask_timeout", &msg.task_id, Some(&msg.subtask_id), None);
+
+        let subscription_id = match &self.subscription_id {
+            Some(subscription_id) => subscription_id,
+            None => return,
+        };
+        if let Err(e) = model::update_subtask_status(
+            &self.db,
+            subscription_id,
+            &msg.task_id,
+            &msg.subtask_id,
+            "timed_out",
+        ) {
+            log::error!("unable to mark subtask {} timed out: {}", msg.subtask_id, e);
+        }
     }
 }
 
+impl Handler<SubtaskFailed> for Gateway {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubtaskFailed, _ctx: &mut Self::Context) -> Self::Result {
+        self.record_event("subtask_failed", &msg.task_id, Some(&msg.subtask_id), Some(msg.reason));
+    }
+}
+
+impl Handler<Stats> for Gateway {
+    type Result = Result<SessionStats, super::error::Error>;
 
+    fn handle(&mut self, _msg: Stats, _ctx: &mut Self::Context) -> Self::Result {
+        let subscription_id = match &self.subscription_id {
+            Some(subscription_id) => subscription_id,
+            None => return Ok(SessionStats::default()),
+        };
+
+        let now = chrono::Utc::now().naive_utc();
+        let counts = |since| {
+            model::task_event_counts(&self.db, subscription_id, since)
+                .map_err(|e| super::error::Error::Other(format!("unable to count events: {}", e)))
+        };
+
+        Ok(SessionStats::from_counts(
+            &counts(chrono::NaiveDateTime::from_timestamp(0, 0))?,
+            &counts(now - chrono::Duration::days(1))?,
+            &counts(now - chrono::Duration::hours(1))?,
+        ))
+    }
+}
+
+fn hub_id() -> String {
+    std::env::var("GU_HUB_ADDR").unwrap_or_else(|_| "127.0.0.1:61622".into())
+}
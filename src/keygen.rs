@@ -3,14 +3,18 @@
 use secp256k1::{SecretKey, PublicKey};
 use rand::{thread_rng, Rng};
 use ethsign;
-use gu_client::NodeId;
 
-pub fn gen_subscription_id() -> NodeId {
+pub fn gen_secret_key() -> ethsign::SecretKey {
     let mut rng = thread_rng();
     let secret = SecretKey::random(&mut rng);
-    let key = ethsign::SecretKey::from_raw(secret.serialize().as_ref()).unwrap();
 
-    key.public().address().clone().into()
+    ethsign::SecretKey::from_raw(secret.serialize().as_ref()).unwrap()
+}
+
+/// Id of the gateway subscription. The gateway keys subscriptions by node id
+/// and task type, so the id changes with the node key.
+pub fn subscription_id(node_id: &str, task_type: &str) -> String {
+    format!("{}/{}", node_id, task_type)
 }
 
 
@@ -20,7 +24,16 @@ mod test {
 
     #[test]
     fn test_gen() {
-        eprintln!("node_id={:?}", super::gen_subscription_id())
+        eprintln!("key={:?}", super::gen_secret_key().public().address())
+    }
+
+    #[test]
+    fn test_subscription_id() {
+        assert_eq!(super::subscription_id("0xab", "Blender"), "0xab/Blender");
+        assert_ne!(
+            super::subscription_id("0xab", "Blender"),
+            super::subscription_id("0xcd", "Blender")
+        );
     }
 
-}
\ No newline at end of file
+}
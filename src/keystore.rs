@@ -0,0 +1,158 @@
+/// Persistent node identity.
+///
+/// Secret key is stored under work dir in ethereum JSON keystore format.
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use ethsign::{KeyFile, SecretKey};
+use rand::{thread_rng, Rng};
+
+use super::error::Error;
+use super::keygen;
+
+const KEY_FILE_NAME: &str = "node-key.json";
+const KEY_ITERATIONS: u32 = 10240;
+
+pub struct Keystore {
+    secret: SecretKey,
+}
+
+/// Address and public key used to identify mediator on the gateway.
+#[derive(Clone, Debug)]
+pub struct NodeIdentity {
+    pub node_id: String,
+    pub eth_public_key: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn keystore_err(e: ethsign::Error) -> Error {
+    Error::Keystore(format!("{:?}", e))
+}
+
+impl Keystore {
+    /// Loads key from `work_dir`, generating and storing a new one on first run.
+    ///
+    /// A new key is stored without password only when `allow_unencrypted` is set.
+    pub fn load_or_create(
+        work_dir: &Path,
+        password: &str,
+        allow_unencrypted: bool,
+    ) -> Result<Keystore, Error> {
+        let path = work_dir.join(KEY_FILE_NAME);
+
+        if path.exists() {
+            let key_file: KeyFile = serde_json::from_reader(fs::File::open(&path)?)?;
+            let secret = key_file
+                .to_secret_key(&password.into())
+                .map_err(keystore_err)?;
+            log::info!("node key loaded from {}", path.display());
+            return Ok(Keystore { secret });
+        }
+
+        if password.is_empty() && !allow_unencrypted {
+            return Err(Error::Keystore(
+                "refusing to store node key without password; \
+                 use --key-password or --insecure-key"
+                    .into(),
+            ));
+        }
+
+        let secret = keygen::gen_secret_key();
+        let key_file = KeyFile {
+            id: gen_key_id(),
+            version: 3,
+            crypto: secret
+                .to_crypto(&password.into(), KEY_ITERATIONS)
+                .map_err(keystore_err)?,
+            address: Some(ethsign::keyfile::Bytes(
+                secret.public().address().to_vec(),
+            )),
+        };
+
+        if !work_dir.as_os_str().is_empty() {
+            fs::create_dir_all(work_dir)?;
+        }
+        serde_json::to_writer_pretty(create_private(&path)?, &key_file)?;
+        log::info!("new node key stored in {}", path.display());
+
+        Ok(Keystore { secret })
+    }
+
+    pub fn identity(&self) -> NodeIdentity {
+        let public = self.secret.public();
+
+        NodeIdentity {
+            node_id: format!("0x{}", to_hex(public.address())),
+            eth_public_key: to_hex(public.bytes()),
+        }
+    }
+}
+
+/// Creates file readable only by its owner.
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+}
+
+fn gen_key_id() -> String {
+    let mut rng = thread_rng();
+    let b: [u8; 16] = rng.gen();
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        to_hex(&b[0..4]),
+        to_hex(&b[4..6]),
+        to_hex(&b[6..8]),
+        to_hex(&b[8..10]),
+        to_hex(&b[10..16])
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_after_create() {
+        let work_dir = std::env::temp_dir().join(format!("gu-keystore-{}", gen_key_id()));
+        assert!(Keystore::load_or_create(&work_dir, "", false).is_err());
+
+        let created = Keystore::load_or_create(&work_dir, "test", false).unwrap();
+        let loaded = Keystore::load_or_create(&work_dir, "test", false).unwrap();
+
+        assert_eq!(created.identity().node_id, loaded.identity().node_id);
+        assert_eq!(created.identity().eth_public_key.len(), 128);
+        assert!(Keystore::load_or_create(&work_dir, "other", false).is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = fs::metadata(work_dir.join(KEY_FILE_NAME))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(&work_dir).unwrap();
+    }
+}
@@ -1,5 +1,7 @@
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 
 use actix::prelude::*;
 use actix_web::{
@@ -8,13 +10,9 @@ use actix_web::{
     Responder,
 };
 use futures::prelude::*;
-use structopt::StructOpt;
 
-use gateway::Gateway;
 use log::Metadata;
 use serde_derive::*;
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
 use serde_json::error::Category::Syntax;
 
 mod args;
@@ -28,24 +26,16 @@ mod subtask_worker;
 mod task_worker;
 mod workman;
 mod keygen;
+mod keystore;
+mod acceptance;
 mod activator;
+mod backoff;
+mod stats;
 
 mod schema;
 mod model;
 
 
-#[derive(Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct SessionConfig {
-    // "account":"0xb2bbb75241939e50b5ba6f698415bbb5ca54610d","davUrl":"http://127.0.0.1:55011","docker":true,"gwUrl":"http://127.0.0.1:55001/"
-    account: String,
-    dav_url: String,
-    gw_url: String,
-    docker: bool,
-    #[serde(default)]
-    subscription_id: String,
-}
-
 fn main() {
 
     if ::std::env::var("RUST_LOG").is_err() {
@@ -54,7 +44,12 @@ fn main() {
 
 
     env_logger::init();
-    let args = args::Args::from_args();
+    let args = args::Args::load().unwrap_or_else(|e| panic!("invalid configuration: {}", e));
+    let work_dir = args.work_dir();
+
+    if ::std::env::var("DATABASE_URL").is_err() {
+        ::std::env::set_var("DATABASE_URL", work_dir.join(model::DATABASE_FILE_NAME))
+    }
 
     let local = args.local;
 
@@ -62,76 +57,104 @@ fn main() {
 
 
     if !local {
-        Arbiter::spawn_fn(|| {
+        let public_url = args.public_url();
+        Arbiter::spawn_fn(move || {
             eprintln!("Starting registration");
-            gu_plugin_api::register_service("http://127.0.0.1:33433/", "gu-blender-mediator")
+            gu_plugin_api::register_service(&public_url, "gu-blender-mediator")
         })
     } else {
         eprintln!("registration skipped");
     }
 
-    let gateways: Arc<RwLock<HashMap<Option<u64>, _>>> = Arc::new(RwLock::new(HashMap::new()));
+    if args.key_password.is_empty() && args.insecure_key {
+        log::warn!(
+            "!!! no key password given: node key in {} is stored unencrypted !!!",
+            work_dir.display()
+        );
+    }
+    let keystore = keystore::Keystore::load_or_create(
+        &work_dir,
+        args.key_password.as_str(),
+        args.insecure_key,
+    )
+    .unwrap_or_else(|e| panic!("unable to load node key: {}", e));
+    let identity = keystore.identity();
+    log::info!("node id: {}", identity.node_id);
+
+    let standalone_config = args
+        .standalone_config(&identity.node_id)
+        .unwrap_or_else(|e| panic!("invalid standalone configuration: {}", e));
+
+    let images = match &args.images {
+        Some(path) => blender::ImageCatalogue::from_file(std::path::Path::new(path))
+            .unwrap_or_else(|e| panic!("unable to load images from {}: {}", path, e)),
+        None => blender::ImageCatalogue::default(),
+    };
+
+    let grace_period = args.grace_period();
+    let activator = activator::Activator::new(
+        gu_client::r#async::HubConnection::default(),
+        identity,
+        images,
+        grace_period,
+    );
+
+    if let Some(config) = standalone_config {
+        let activator = activator.clone();
+        Arbiter::spawn_fn(move || {
+            activator.activate_standalone(config).then(|r| {
+                if let Err(e) = r {
+                    log::error!("unable to start standalone gateway: {}", e);
+                    System::current().stop();
+                }
+                Ok::<(), ()>(())
+            })
+        });
+    } else {
+        let activator = activator.clone();
+        Arbiter::spawn_fn(move || {
+            activator
+                .restore_sessions()
+                .map_err(|e| log::error!("unable to restore sessions: {}", e))
+        });
+    }
+
+    let listen_addr = args.listen_addr();
+    eprintln!("http://{}/", listen_addr);
 
-    eprintln!("http://127.0.0.1:33433/");
+    let activator_to_shutdown = activator.clone();
 
     let s = HttpServer::new(move || {
-        let gateways_to_add = gateways.clone();
-        let gateways_to_get = gateways.clone();
-        let gateways_to_get2 = gateways.clone();
+        let activator_to_add = activator.clone();
+        let activator_to_get = activator.clone();
+        let activator_to_get2 = activator.clone();
+        let activator_to_stop = activator.clone();
+        let activator_to_pause = activator.clone();
+        let activator_to_resume = activator.clone();
 
         App::new()
             .wrap(actix_web::middleware::Logger::default())
             .service(
                 web::resource("/gw")
                     .route(web::post().to_async(move |b: web::Json<u64>| {
-                        let gateways = gateways_to_add.clone();
-                        use gu_client::r#async::*;
-                        let gu_api = HubConnection::default();
                         let session_id = b.into_inner();
 
-                        let session = gu_api.hub_session(session_id);
-                        session
-                            .config()
+                        activator_to_add
+                            .activate_gateway(session_id)
                             .map_err(|e| actix_web::error::ErrorInternalServerError(e))
-                            .and_then(move |m| {
-                                fn extract_config(
-                                    m: gu_client::model::session::Metadata,
-                                ) -> Result<SessionConfig, serde_json::Error>
-                                {
-                                    serde_json::from_value(serde_json::to_value(m.entry)?)
-                                }
-
-                                let config: SessionConfig = match extract_config(m) {
-                                    Ok(c) => c,
-                                    Err(e) => {
-                                        return Err(actix_web::error::ErrorInternalServerError(e));
-                                    }
-                                };
-
-                                let gw =
-                                    Gateway::new(Some(session_id), config.dav_url, config.gw_url, config.account)
-                                        .start();
-                                gateways.write().unwrap().insert(Some(session_id), gw);
-                                Ok(HttpResponse::Ok().json("ok"))
-                            })
+                            .and_then(|_| Ok(HttpResponse::Ok().json("ok")))
                     }))
                     .route(web::get().to(move |_: ()| {
-                        let sessions: Vec<Option<u64>> =
-                            gateways_to_get.read().unwrap().keys().cloned().collect();
+                        let sessions: Vec<u64> = activator_to_get.active_sessions();
 
                         web::Json(sessions)
                     })),
             )
             .service(web::resource("/gw/{session_id}").route(web::get().to_async(
                 move |p: web::Path<(u64,)>| {
-                    let gw = {
-                        gateways_to_get2
-                            .read()
-                            .unwrap()
-                            .get(&Some(p.0))
-                            .ok_or(error::other("missing id"))
-                            .map(|v| v.clone())
-                    };
+                    let gw = activator_to_get2
+                        .session_gateway(p.0)
+                        .ok_or(error::other("missing id"));
                     let request = {
                         gw.into_future()
                             .and_then(|a| a.send(gateway::Stats).flatten())
@@ -141,12 +164,83 @@ fn main() {
                         .map_err(|e| actix_web::error::ErrorInternalServerError(e))
                         .and_then(|stats| Ok(HttpResponse::Ok().json(stats)))
                 },
+            )).route(web::delete().to_async(
+                move |p: web::Path<(u64,)>| {
+                    activator_to_stop
+                        .stop_gateway(p.0)
+                        .map_err(|e| actix_web::error::ErrorInternalServerError(e))
+                        .and_then(|_| Ok(HttpResponse::Ok().json("ok")))
+                },
+            )))
+            .service(web::resource("/gw/{session_id}/pause").route(web::post().to_async(
+                move |p: web::Path<(u64,)>| {
+                    activator_to_pause
+                        .pause_gateway(p.0)
+                        .map_err(|e| actix_web::error::ErrorInternalServerError(e))
+                        .and_then(|_| Ok(HttpResponse::Ok().json("ok")))
+                },
+            )))
+            .service(web::resource("/gw/{session_id}/resume").route(web::post().to_async(
+                move |p: web::Path<(u64,)>| {
+                    activator_to_resume
+                        .resume_gateway(p.0)
+                        .map_err(|e| actix_web::error::ErrorInternalServerError(e))
+                        .and_then(|_| Ok(HttpResponse::Ok().json("ok")))
+                },
             )))
+            .service(web::resource("/peers").route(web::get().to_async(|| {
+                workman::scoreboard()
+                    .map_err(|e| actix_web::error::ErrorInternalServerError(e))
+                    .and_then(|scores| Ok(HttpResponse::Ok().json(scores)))
+            })))
     })
-    .bind("127.0.0.1:33433")
-    .unwrap()
+    .disable_signals()
+    .bind(&listen_addr)
+    .unwrap_or_else(|e| panic!("unable to bind {}: {}", listen_addr, e))
     .start();
 
+    Arbiter::spawn(
+        shutdown_signal()
+            .and_then(move |_| {
+                log::info!("shutting down, grace period {:?}", grace_period);
+                activator_to_shutdown.shutdown(grace_period)
+            })
+            .and_then(move |_| s.stop(true))
+            .then(|_| {
+                System::current().stop();
+                Ok(())
+            }),
+    );
+
     sys.run().unwrap();
 
 }
+
+/// Resolves on first SIGTERM or SIGINT. Never resolves when signals cannot be handled.
+#[cfg(unix)]
+fn shutdown_signal() -> impl Future<Item = (), Error = ()> {
+    use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+    Signal::new(SIGTERM)
+        .flatten_stream()
+        .select(Signal::new(SIGINT).flatten_stream())
+        .into_future()
+        .map(|(signal, _)| log::info!("received signal {:?}", signal))
+        .or_else(|(e, _)| {
+            log::error!("signal handling failed: {}", e);
+            futures::future::empty()
+        })
+}
+
+/// Resolves on first Ctrl-C. Never resolves when it cannot be handled.
+#[cfg(not(unix))]
+fn shutdown_signal() -> impl Future<Item = (), Error = ()> {
+    tokio_signal::ctrl_c()
+        .flatten_stream()
+        .into_future()
+        .map(|_| log::info!("received ctrl-c"))
+        .or_else(|(e, _)| {
+            log::error!("signal handling failed: {}", e);
+            futures::future::empty()
+        })
+}
@@ -1,11 +1,13 @@
 use diesel::prelude::*;
-use super::schema::{subscriptions, subscription_tasks, subscription_event};
+use super::error::Error;
+use super::schema::{subscriptions, subscription_tasks, subscription_subtask, subscription_event, peer_reservation};
 
 #[derive(Queryable, Insertable, Debug)]
 pub struct Subscription {
     pub hub_id : String,
     pub session_id : i32,
-    pub subscription_id : String
+    pub subscription_id : String,
+    pub last_event_id : i64
 }
 
 #[derive(Queryable, Insertable, Debug)]
@@ -18,6 +20,17 @@ pub struct SubscriptionTask {
     pub max_price_gnt : Option<f64>
 }
 
+#[derive(Queryable, Insertable, Debug)]
+#[table_name="subscription_subtask"]
+pub struct SubscriptionSubtask {
+    pub subscription_id : String,
+    pub task_id : String,
+    pub subtask_id : String,
+    pub price_gnt : Option<f64>,
+    pub deadline : Option<chrono::NaiveDateTime>,
+    pub status : String
+}
+
 #[derive(Queryable, Debug)]
 pub struct SubscriptionEvent {
     pub event_id : i32,
@@ -40,26 +53,255 @@ pub struct NewSubscriptionEvent {
     pub event_desc : Option<String>
 }
 
-//pub struct
+/// Peer slot held by a task; `node_id` is hex encoded.
+#[derive(Queryable, Insertable, Debug)]
+#[table_name="peer_reservation"]
+pub struct PeerReservation {
+    pub node_id : String,
+    pub slot : i32,
+    pub task_id : String,
+    pub subtask_id : Option<String>,
+    pub reserved_until : chrono::NaiveDateTime,
+    pub owner : Option<String>
+}
+
+embed_migrations!();
 
-pub fn establish_connection() -> SqliteConnection {
+pub const DATABASE_FILE_NAME : &str = "gu-blender-mediator.db";
+
+/// Connects to the database given by `DATABASE_URL`, defaulting to `DATABASE_FILE_NAME` in current dir.
+pub fn establish_connection() -> Result<SqliteConnection, Error> {
     use std::env;
 
-    let database_url = env::var("DATABASE_URL").unwrap();
+    let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| DATABASE_FILE_NAME.into());
+
+    let connection = SqliteConnection::establish(&database_url)
+        .map_err(|e| Error::Database(format!("unable to connect to {}: {}", database_url, e)))?;
+
+    embedded_migrations::run(&connection)
+        .map_err(|e| Error::Database(format!("unable to migrate {}: {}", database_url, e)))?;
+
+    Ok(connection)
+}
+
+pub fn find_subscription(connection : &SqliteConnection, subscription : &str) -> QueryResult<Option<Subscription>> {
+    use super::schema::subscriptions::dsl::*;
+
+    subscriptions
+        .filter(subscription_id.eq(subscription))
+        .first(connection)
+        .optional()
+}
+
+pub fn insert_subscription(connection : &SqliteConnection, subscription : &Subscription) -> QueryResult<()> {
+    use super::schema::subscriptions::dsl::*;
+
+    diesel::insert_into(subscriptions)
+        .values(subscription)
+        .execute(connection)
+        .map(|_| ())
+}
+
+pub fn update_subscription_session(connection : &SqliteConnection, subscription : &str, session : i32) -> QueryResult<()> {
+    use super::schema::subscriptions::dsl::*;
+
+    diesel::update(subscriptions.filter(subscription_id.eq(subscription)))
+        .set(session_id.eq(session))
+        .execute(connection)
+        .map(|_| ())
+}
+
+pub fn update_last_event_id(connection : &SqliteConnection, subscription : &str, event_id : i64) -> QueryResult<()> {
+    use super::schema::subscriptions::dsl::*;
+
+    diesel::update(subscriptions.filter(subscription_id.eq(subscription)))
+        .set(last_event_id.eq(event_id))
+        .execute(connection)
+        .map(|_| ())
+}
+
+/// Records accepted task. Task events replayed by the gateway are ignored.
+pub fn insert_task(connection : &SqliteConnection, task : &SubscriptionTask) -> QueryResult<()> {
+    use super::schema::subscription_tasks::dsl::*;
+
+    diesel::insert_or_ignore_into(subscription_tasks)
+        .values(task)
+        .execute(connection)
+        .map(|_| ())
+}
+
+/// Tasks of given subscription with deadline not earlier than `now`, which were not
+/// recorded as finished.
+pub fn active_tasks(connection : &SqliteConnection, subscription : &str, now : chrono::NaiveDateTime) -> QueryResult<Vec<SubscriptionTask>> {
+    use super::schema::subscription_tasks::dsl::*;
+
+    let finished_tasks = subscription_event::table
+        .filter(subscription_event::subscription_id.eq(subscription))
+        .filter(subscription_event::event_type.eq("task_finished"))
+        .select(subscription_event::task_id);
+
+    subscription_tasks
+        .filter(subscription_id.eq(subscription))
+        .filter(deadline.is_null().or(deadline.ge(now)))
+        .filter(task_id.ne_all(finished_tasks))
+        .load(connection)
+}
+
+/// Records received subtask. Subtask events replayed by the gateway are ignored.
+pub fn insert_subtask(connection : &SqliteConnection, subtask : &SubscriptionSubtask) -> QueryResult<()> {
+    use super::schema::subscription_subtask::dsl::*;
+
+    diesel::insert_or_ignore_into(subscription_subtask)
+        .values(subtask)
+        .execute(connection)
+        .map(|_| ())
+}
+
+pub fn update_subtask_status(connection : &SqliteConnection, subscription : &str, task : &str, subtask : &str, new_status : &str) -> QueryResult<()> {
+    use super::schema::subscription_subtask::dsl::*;
+
+    diesel::update(subscription_subtask
+        .filter(subscription_id.eq(subscription))
+        .filter(task_id.eq(task))
+        .filter(subtask_id.eq(subtask)))
+        .set(status.eq(new_status))
+        .execute(connection)
+        .map(|_| ())
+}
+
+pub fn insert_event(connection : &SqliteConnection, event : &NewSubscriptionEvent) -> QueryResult<()> {
+    use super::schema::subscription_event::dsl::*;
+
+    diesel::insert_into(subscription_event)
+        .values(event)
+        .execute(connection)
+        .map(|_| ())
+}
+
+pub fn last_event_of_type(connection : &SqliteConnection, subscription : &str, task : &str, ev_type : &str) -> QueryResult<Option<SubscriptionEvent>> {
+    use super::schema::subscription_event::dsl::*;
+
+    subscription_event
+        .filter(subscription_id.eq(subscription))
+        .filter(task_id.eq(task))
+        .filter(event_type.eq(ev_type))
+        .order(event_id.desc())
+        .first(connection)
+        .optional()
+}
+
+/// Hub session created by the standalone gateway of the node.
+pub fn find_standalone_session(connection : &SqliteConnection, node : &str) -> QueryResult<Option<u64>> {
+    use super::schema::standalone_session::dsl::*;
+
+    standalone_session
+        .filter(node_id.eq(node))
+        .select(session_id)
+        .first::<i64>(connection)
+        .optional()
+        .map(|session| session.map(|session| session as u64))
+}
+
+pub fn store_standalone_session(connection : &SqliteConnection, node : &str, session : u64) -> QueryResult<()> {
+    use super::schema::standalone_session::dsl::*;
+
+    diesel::replace_into(standalone_session)
+        .values((node_id.eq(node), session_id.eq(session as i64)))
+        .execute(connection)
+        .map(|_| ())
+}
+
+/// Event counts of a single task, as aggregated by `task_event_counts`.
+#[derive(QueryableByName, Debug, Clone, PartialEq)]
+pub struct TaskEventCounts {
+    #[sql_type = "diesel::sql_types::Text"]
+    pub task_id : String,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub tasks : i64,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub subtasks : i64,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub subtasks_done : i64,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub fails : i64
+}
+
+/// Counts events of the subscription recorded since given time, grouped by task.
+pub fn task_event_counts(connection : &SqliteConnection, subscription : &str, since : chrono::NaiveDateTime) -> QueryResult<Vec<TaskEventCounts>> {
+    use diesel::sql_types::{Text, Timestamp};
+
+    diesel::sql_query("SELECT task_id, \
+            COUNT(DISTINCT CASE WHEN event_type = 'task' THEN task_id END) AS tasks, \
+            COUNT(DISTINCT CASE WHEN event_type = 'subtask' THEN subtask_id END) AS subtasks, \
+            COUNT(CASE WHEN event_type = 'subtask_verification' AND event_desc = 'OK' THEN 1 END) AS subtasks_done, \
+            COUNT(CASE WHEN event_type = 'subtask_failed' THEN 1 END) AS fails \
+        FROM subscription_event \
+        WHERE subscription_id = ? AND ts >= ? \
+        GROUP BY task_id")
+        .bind::<Text, _>(subscription)
+        .bind::<Timestamp, _>(since)
+        .load(connection)
+}
+
+/// Stores reservation, replacing previous one of the same peer slot.
+pub fn insert_reservation(connection : &SqliteConnection, reservation : &PeerReservation) -> QueryResult<()> {
+    use super::schema::peer_reservation::dsl::*;
+
+    diesel::replace_into(peer_reservation)
+        .values(reservation)
+        .execute(connection)
+        .map(|_| ())
+}
+
+pub fn update_reservation_subtask(connection : &SqliteConnection, node : &str, slot_index : i32, task : &str, subtask : Option<&str>) -> QueryResult<()> {
+    use super::schema::peer_reservation::dsl::*;
+
+    diesel::update(peer_reservation
+        .filter(node_id.eq(node))
+        .filter(slot.eq(slot_index))
+        .filter(task_id.eq(task)))
+        .set(subtask_id.eq(subtask))
+        .execute(connection)
+        .map(|_| ())
+}
+
+pub fn delete_reservation(connection : &SqliteConnection, node : &str, slot_index : i32, task : &str) -> QueryResult<()> {
+    use super::schema::peer_reservation::dsl::*;
 
-    SqliteConnection::establish(&database_url)
-        .expect(&format!("Error connecting to {}", database_url))
+    diesel::delete(peer_reservation
+        .filter(node_id.eq(node))
+        .filter(slot.eq(slot_index))
+        .filter(task_id.eq(task)))
+        .execute(connection)
+        .map(|_| ())
+}
+
+/// Removes reservations which ended before `now`.
+pub fn delete_expired_reservations(connection : &SqliteConnection, now : chrono::NaiveDateTime) -> QueryResult<()> {
+    use super::schema::peer_reservation::dsl::*;
+
+    diesel::delete(peer_reservation.filter(reserved_until.lt(now)))
+        .execute(connection)
+        .map(|_| ())
+}
+
+pub fn active_reservations(connection : &SqliteConnection, now : chrono::NaiveDateTime) -> QueryResult<Vec<PeerReservation>> {
+    use super::schema::peer_reservation::dsl::*;
+
+    peer_reservation
+        .filter(reserved_until.ge(now))
+        .load(connection)
 }
 
 #[cfg(test)]
 #[test]
 fn test_insert() {
-    let connection = establish_connection();
+    let connection = establish_connection().unwrap();
     use super::model::SubscriptionEvent;
     use super::schema::subscription_event::dsl::*;
 
     let data = NewSubscriptionEvent {
-        subscription_id: super::keygen::gen_subscription_id().to_string(),
+        subscription_id: super::keygen::subscription_id("0x00", "Test"),
         task_id: "smok-123".into(),
         subtask_id: None,
         event_type: "Test".into(),
@@ -77,7 +319,7 @@ fn test_insert() {
 #[cfg(test)]
 #[test]
 fn test_query() {
-    let connection = establish_connection();
+    let connection = establish_connection().unwrap();
     use super::model::SubscriptionEvent;
     use super::schema::subscription_event::dsl::*;
 
@@ -93,4 +335,73 @@ fn test_query() {
         let _ = subscriptions.load::<Subscription>(&connection).unwrap();
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+#[test]
+fn test_active_tasks() {
+    let connection = SqliteConnection::establish(":memory:").unwrap();
+    embedded_migrations::run(&connection).unwrap();
+
+    let now = chrono::Utc::now().naive_utc();
+    let task = |task : &str, deadline : chrono::NaiveDateTime| SubscriptionTask {
+        subscription_id: "s".into(),
+        task_id: task.into(),
+        deadline: Some(deadline),
+        resource_size: None,
+        estimated_memory: None,
+        max_price_gnt: None
+    };
+    insert_task(&connection, &task("running", now + chrono::Duration::hours(1))).unwrap();
+    insert_task(&connection, &task("finished", now + chrono::Duration::hours(1))).unwrap();
+    insert_task(&connection, &task("expired", now - chrono::Duration::hours(1))).unwrap();
+    insert_event(&connection, &NewSubscriptionEvent {
+        subscription_id: "s".into(),
+        task_id: "finished".into(),
+        subtask_id: None,
+        event_type: "task_finished".into(),
+        event_desc: None
+    }).unwrap();
+
+    let tasks : Vec<String> = active_tasks(&connection, "s", now).unwrap().into_iter().map(|t| t.task_id).collect();
+    assert_eq!(tasks, vec!["running".to_string()]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_task_event_counts() {
+    let connection = SqliteConnection::establish(":memory:").unwrap();
+    embedded_migrations::run(&connection).unwrap();
+
+    let event = |task : &str, subtask : Option<&str>, event_type : &str, desc : Option<&str>| NewSubscriptionEvent {
+        subscription_id: "s".into(),
+        task_id: task.into(),
+        subtask_id: subtask.map(Into::into),
+        event_type: event_type.into(),
+        event_desc: desc.map(Into::into)
+    };
+    let events = vec![
+        event("t1", None, "task", None),
+        event("t1", Some("s1"), "subtask", None),
+        event("t1", Some("s1"), "subtask_verification", Some("OK")),
+        event("t1", Some("s2"), "subtask", None),
+        event("t1", Some("s2"), "subtask", None),
+        event("t1", Some("s2"), "subtask_failed", Some("render failed")),
+        event("t2", Some("s3"), "subtask_verification", Some("FAILED")),
+    ];
+    diesel::insert_into(subscription_event::table)
+        .values(&events)
+        .execute(&connection)
+        .unwrap();
+
+    let now = chrono::Utc::now().naive_utc();
+    let mut counts = task_event_counts(&connection, "s", now - chrono::Duration::hours(1)).unwrap();
+    counts.sort_by(|a, b| a.task_id.cmp(&b.task_id));
+    assert_eq!(counts, vec![
+        TaskEventCounts { task_id: "t1".into(), tasks: 1, subtasks: 2, subtasks_done: 1, fails: 1 },
+        TaskEventCounts { task_id: "t2".into(), tasks: 0, subtasks: 0, subtasks_done: 0, fails: 0 },
+    ]);
+
+    assert!(task_event_counts(&connection, "s", now + chrono::Duration::hours(1)).unwrap().is_empty());
+    assert!(task_event_counts(&connection, "other", now - chrono::Duration::hours(1)).unwrap().is_empty());
+}
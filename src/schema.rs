@@ -1,3 +1,21 @@
+table! {
+    peer_reservation (node_id, slot) {
+        node_id -> Text,
+        slot -> Integer,
+        task_id -> Text,
+        subtask_id -> Nullable<Text>,
+        reserved_until -> Timestamp,
+        owner -> Nullable<Text>,
+    }
+}
+
+table! {
+    standalone_session (node_id) {
+        node_id -> Text,
+        session_id -> BigInt,
+    }
+}
+
 table! {
     subscription_event (event_id) {
         event_id -> Integer,
@@ -17,6 +35,7 @@ table! {
         subtask_id -> Text,
         price_gnt -> Nullable<Double>,
         deadline -> Nullable<Timestamp>,
+        status -> Text,
     }
 }
 
@@ -36,12 +55,15 @@ table! {
         hub_id -> Text,
         session_id -> Integer,
         subscription_id -> Text,
+        last_event_id -> BigInt,
     }
 }
 
 joinable!(subscription_tasks -> subscriptions (subscription_id));
 
 allow_tables_to_appear_in_same_query!(
+    peer_reservation,
+    standalone_session,
     subscription_event,
     subscription_subtask,
     subscription_tasks,
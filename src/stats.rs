@@ -0,0 +1,101 @@
+use super::model::TaskEventCounts;
+use serde_derive::*;
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StatsData {
+    pub tasks: u64,
+    pub subtasks: u64,
+    pub subtasks_done: u64,
+    pub fails: u64,
+}
+
+/// Statistics of a gateway session, computed from counts of recorded subscription events.
+///
+/// Lifetime totals are flattened into the top level, so clients reading
+/// `tasks`, `subtasks`, `subtasksDone` and `fails` see the whole session history.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionStats {
+    #[serde(flatten)]
+    pub total: StatsData,
+    pub last_hour: StatsData,
+    pub last_day: StatsData,
+    pub per_task: BTreeMap<String, StatsData>,
+}
+
+impl StatsData {
+    fn from_counts(counts: &TaskEventCounts) -> Self {
+        StatsData {
+            tasks: counts.tasks as u64,
+            subtasks: counts.subtasks as u64,
+            subtasks_done: counts.subtasks_done as u64,
+            fails: counts.fails as u64,
+        }
+    }
+
+    fn sum<'a>(counts: impl IntoIterator<Item = &'a TaskEventCounts>) -> Self {
+        counts.into_iter().map(StatsData::from_counts).fold(
+            StatsData::default(),
+            |acc, data| StatsData {
+                tasks: acc.tasks + data.tasks,
+                subtasks: acc.subtasks + data.subtasks,
+                subtasks_done: acc.subtasks_done + data.subtasks_done,
+                fails: acc.fails + data.fails,
+            },
+        )
+    }
+}
+
+impl SessionStats {
+    /// Builds statistics from per task event counts aggregated over the whole
+    /// session, the last day and the last hour.
+    pub fn from_counts(
+        total: &[TaskEventCounts],
+        last_day: &[TaskEventCounts],
+        last_hour: &[TaskEventCounts],
+    ) -> Self {
+        SessionStats {
+            total: StatsData::sum(total),
+            last_hour: StatsData::sum(last_hour),
+            last_day: StatsData::sum(last_day),
+            per_task: total
+                .iter()
+                .map(|counts| (counts.task_id.clone(), StatsData::from_counts(counts)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn counts(task_id: &str, tasks: i64, subtasks: i64, subtasks_done: i64, fails: i64) -> TaskEventCounts {
+        TaskEventCounts {
+            task_id: task_id.into(),
+            tasks,
+            subtasks,
+            subtasks_done,
+            fails,
+        }
+    }
+
+    #[test]
+    fn test_from_counts() {
+        let total = vec![counts("t1", 1, 2, 1, 1), counts("t2", 1, 1, 0, 0)];
+        let last_hour = vec![counts("t1", 0, 1, 0, 1), counts("t2", 1, 1, 0, 0)];
+
+        let stats = SessionStats::from_counts(&total, &total, &last_hour);
+
+        assert_eq!(stats.total, StatsData { tasks: 2, subtasks: 3, subtasks_done: 1, fails: 1 });
+        assert_eq!(stats.last_hour, StatsData { tasks: 1, subtasks: 2, subtasks_done: 0, fails: 1 });
+        assert_eq!(stats.last_day, stats.total);
+        assert_eq!(stats.per_task["t1"], StatsData { tasks: 1, subtasks: 2, subtasks_done: 1, fails: 1 });
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["subtasksDone"], 1);
+        assert_eq!(json["lastHour"]["fails"], 1);
+    }
+}
@@ -1,31 +1,112 @@
 use super::blender;
-use super::{dav, joinact, workman};
+use super::error::Error;
+use super::gateway::{
+    EnvironmentAvailable, EnvironmentUnavailable, Gateway, SubtaskFailed, SubtaskTimedOut,
+    TaskFinished, WorkerStopped,
+};
+use super::dav;
+use super::workman::{self, SlotId};
 use actix::prelude::*;
-use futures::prelude::*;
+use failure::Fail;
+use futures::{future, prelude::*, sync::oneshot};
 use golem_gw_api::models::Subtask;
 use gu_client::NodeId;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub struct TaskWorker {
     dav_url: String,
     api: Rc<dyn golem_gw_api::apis::DefaultApi>,
     hub_session: gu_client::r#async::HubSession,
-    deployment: Option<gu_client::r#async::PeerSession>,
-    spec: Option<blender::BlenderSubtaskSpec>,
     task: golem_gw_api::models::Task,
-    subtask_id: Option<String>,
     node_id: String,
-    peer_id: Option<NodeId>,
-    state: State,
+    max_slots: usize,
+    deadline_margin: Duration,
+    peer_selection: workman::SelectionStrategy,
+    slots_per_peer: Option<usize>,
+    peers: HashMap<SlotId, PeerSlot>,
+    subtasks: HashMap<String, SubtaskSlot>,
+    /// Subtasks reported as failed because no peer was free to compute them.
+    unplaced: HashSet<String>,
+    resource_uri: Option<String>,
     output_uri: String,
-    cnt: Counters,
+    docker: bool,
+    images: Arc<blender::ImageCatalogue>,
+    gateway: Addr<Gateway>,
+    drained: Option<oneshot::Sender<()>>,
+    drain_check: Option<SpawnHandle>,
+}
+
+/// Reserved peer slot with its own blender deployment; the task may hold several
+/// slots of the same peer. Computes at most one subtask at a time.
+struct PeerSlot {
+    deployment: Option<gu_client::r#async::PeerSession>,
+    image: Option<blender::ImageSpec>,
+    resource_ready: bool,
+    subtask_id: Option<String>,
+}
+
+/// Subtask in flight, bound to a peer slot.
+struct SubtaskSlot {
+    slot_id: SlotId,
+    spec: Option<blender::BlenderSubtaskSpec>,
+    spec_ready: bool,
+    running: bool,
+    result_sent: bool,
+    compute: Option<SpawnHandle>,
+    watchdog: Option<SpawnHandle>,
+    /// Unix timestamp in seconds.
+    deadline: u64,
+    /// Peers the subtask failed on, excluded when it is moved to another peer.
+    failed_peers: Vec<NodeId>,
 }
 
-#[derive(Default)]
-struct Counters {
-    subtasks_cnt: u64,
-    subtasks_done_cnt: u64,
-    subtasks_fail_cnt: u64,
+/// Peers a single subtask is tried on before it is reported as failed.
+const MAX_SUBTASK_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Fail)]
+enum SubtaskFailure {
+    #[fail(display = "blender deployment failed: {}", _0)]
+    Deployment(String),
+    #[fail(display = "resource download failed: {}", _0)]
+    Download(String),
+    #[fail(display = "subtask spec upload failed: {}", _0)]
+    SpecUpload(String),
+    #[fail(display = "render failed: {}", _0)]
+    Render(String),
+    #[fail(display = "missing output file: {}", _0)]
+    MissingOutput(String),
+    #[fail(display = "environment unavailable: {}", _0)]
+    Environment(String),
+    #[fail(display = "invalid subtask spec: {}", _0)]
+    InvalidSpec(String),
+    #[fail(display = "deadline exceeded")]
+    Timeout,
+    #[fail(display = "mediator shutdown")]
+    Shutdown,
+}
+
+impl SubtaskFailure {
+    /// Kind of failure attributed to the peer computing the subtask, if any.
+    fn peer_failure(&self) -> Option<workman::PeerFailure> {
+        match self {
+            SubtaskFailure::Deployment(_) => Some(workman::PeerFailure::Deployment),
+            SubtaskFailure::Download(_)
+            | SubtaskFailure::SpecUpload(_)
+            | SubtaskFailure::Render(_)
+            | SubtaskFailure::MissingOutput(_)
+            | SubtaskFailure::Timeout => Some(workman::PeerFailure::Render),
+            SubtaskFailure::Environment(_)
+            | SubtaskFailure::InvalidSpec(_)
+            | SubtaskFailure::Shutdown => None,
+        }
+    }
+
+    fn is_peer_fault(&self) -> bool {
+        self.peer_failure().is_some()
+    }
 }
 
 pub struct DoSubTask(pub Subtask);
@@ -46,6 +127,13 @@ impl Message for DoSubtaskVerification {
     type Result = Result<(), gu_client::error::Error>;
 }
 
+/// Stops worker, destroying its deployment.
+pub struct StopTask;
+
+impl Message for StopTask {
+    type Result = ();
+}
+
 impl TaskWorker {
     pub fn new(
         dav_url: String,
@@ -53,6 +141,13 @@ impl TaskWorker {
         hub_session: gu_client::r#async::HubSession,
         node_id: &str,
         task: &golem_gw_api::models::Task,
+        docker: bool,
+        max_slots: usize,
+        deadline_margin: Duration,
+        peer_selection: workman::SelectionStrategy,
+        slots_per_peer: Option<usize>,
+        images: Arc<blender::ImageCatalogue>,
+        gateway: Addr<Gateway>,
     ) -> Self {
         TaskWorker {
             dav_url,
@@ -60,169 +155,822 @@ impl TaskWorker {
             hub_session,
             node_id: node_id.to_owned(),
             task: task.clone(),
-            peer_id: None,
-            deployment: None,
-            state: State::default(),
+            max_slots,
+            deadline_margin,
+            peer_selection,
+            slots_per_peer,
+            peers: HashMap::new(),
+            subtasks: HashMap::new(),
+            unplaced: HashSet::new(),
+            resource_uri: None,
             output_uri: String::default(),
-            spec: None,
-            subtask_id: None,
-            cnt: Counters::default(),
+            docker,
+            images,
+            gateway,
+            drained: None,
+            drain_check: None,
         }
     }
 
-    fn resource_ready(&mut self, ctx: &mut <Self as Actor>::Context) {
-        self.state.resource_ready = true;
-        self.start_processing(ctx)
+    /// Binds subtask to a free peer slot, or returns the peer it is already bound to.
+    /// Subtasks already reported as unplaced are never bound.
+    fn bind_subtask(&mut self, subtask_id: &str) -> Option<SlotId> {
+        if let Some(subtask) = self.subtasks.get(subtask_id) {
+            return Some(subtask.slot_id);
+        }
+        if self.unplaced.contains(subtask_id) {
+            return None;
+        }
+
+        let slot_id = self
+            .peers
+            .iter()
+            .filter(|(_, slot)| slot.subtask_id.is_none() && slot.deployment.is_some())
+            .map(|(slot_id, _)| *slot_id)
+            .next()?;
+
+        self.peers.get_mut(&slot_id).unwrap().subtask_id = Some(subtask_id.to_owned());
+        workman::assign_subtask(self.task.task_id(), slot_id, Some(subtask_id));
+        self.subtasks.insert(
+            subtask_id.to_owned(),
+            SubtaskSlot {
+                slot_id,
+                spec: None,
+                spec_ready: false,
+                running: false,
+                result_sent: false,
+                compute: None,
+                watchdog: None,
+                deadline: (*self.task.deadline()) as u64,
+                failed_peers: Vec::new(),
+            },
+        );
+        Some(slot_id)
     }
 
-    fn spec_ready(&mut self, ctx: &mut <Self as Actor>::Context) {
-        self.state.spec_ready = true;
-        self.start_processing(ctx)
+    /// Unbinds subtask from its peer slot; returns the freed peer.
+    fn finish_subtask(
+        &mut self,
+        subtask_id: &str,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Option<SlotId> {
+        let subtask = self.subtasks.remove(subtask_id)?;
+        if let Some(watchdog) = subtask.watchdog {
+            ctx.cancel_future(watchdog);
+        }
+        if let Some(slot) = self.peers.get_mut(&subtask.slot_id) {
+            slot.subtask_id = None;
+            workman::assign_subtask(self.task.task_id(), subtask.slot_id, None);
+        }
+        Some(subtask.slot_id)
     }
 
-    fn start_processing(&mut self, ctx: &mut <Self as Actor>::Context) {
-        use gu_client::model::envman::{Command, ResourceFormat};
+    /// Time left until the subtask has to be aborted: the earlier of task and
+    /// subtask deadline minus the safety margin.
+    fn time_left(&self, subtask_deadline: u64) -> Duration {
+        let deadline = UNIX_EPOCH
+            + Duration::from_secs(subtask_deadline.min((*self.task.deadline()) as u64));
+        deadline
+            .checked_sub(self.deadline_margin)
+            .and_then(|abort_at| abort_at.duration_since(SystemTime::now()).ok())
+            .unwrap_or_default()
+    }
 
-        log::debug!("task state is_ready={}", self.state.is_ready());
+    fn start_watchdog(
+        &mut self,
+        subtask_id: &str,
+        subtask_deadline: u64,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let time_left = self.time_left(subtask_deadline);
+        log::debug!("subtask {} has to be done in {:?}", subtask_id, time_left);
+
+        let watchdog = {
+            let subtask_id = subtask_id.to_owned();
+            ctx.run_later(time_left, move |act, ctx| act.abort_subtask(subtask_id, ctx))
+        };
+        if let Some(subtask) = self.subtasks.get_mut(subtask_id) {
+            subtask.watchdog = Some(watchdog);
+        }
+    }
+
+    fn stop_watchdog(&mut self, subtask_id: &str, ctx: &mut <Self as Actor>::Context) {
+        if let Some(watchdog) = self
+            .subtasks
+            .get_mut(subtask_id)
+            .and_then(|subtask| subtask.watchdog.take())
+        {
+            ctx.cancel_future(watchdog);
+        }
+    }
+
+    /// Aborts subtask which missed its deadline. The peer is released, since
+    /// destroying its deployment is the only way to stop a running command.
+    fn abort_subtask(&mut self, subtask_id: String, ctx: &mut <Self as Actor>::Context) {
+        let subtask = match self.subtasks.remove(&subtask_id) {
+            Some(subtask) => subtask,
+            None => return,
+        };
+        log::warn!(
+            "subtask {} timed out @ peer {:?}",
+            subtask_id,
+            subtask.slot_id
+        );
+        if let Some(compute) = subtask.compute {
+            ctx.cancel_future(compute);
+        }
+        workman::record_failure(subtask.slot_id.node_id, workman::PeerFailure::Render);
+        self.release_slot(subtask.slot_id);
+        self.gateway.do_send(SubtaskTimedOut {
+            task_id: self.task.task_id().to_owned(),
+            subtask_id: subtask_id.clone(),
+        });
+        self.report_failure(subtask_id, SubtaskFailure::Timeout, ctx);
 
-        if !self.state.is_ready() {
+        if self.drained.is_some() {
             return;
         }
+        if self.time_left((*self.task.deadline()) as u64) > Duration::from_secs(0) {
+            ctx.spawn(self.add_slot());
+        } else if self.peers.is_empty() {
+            ctx.stop();
+        }
+    }
 
-        let deployment = match self.deployment.as_ref() {
-            Some(d) => d.clone(),
-            None => {
-                log::error!("!!! deployment not ready !!!");
+    fn resource_ready(&mut self, slot_id: SlotId, ctx: &mut <Self as Actor>::Context) {
+        let subtask_id = match self.peers.get_mut(&slot_id) {
+            Some(slot) => {
+                slot.resource_ready = true;
+                slot.subtask_id.clone()
+            }
+            None => return,
+        };
+        if let Some(subtask_id) = subtask_id {
+            self.start_processing(subtask_id, ctx)
+        }
+    }
+
+    fn spec_ready(&mut self, subtask_id: String, ctx: &mut <Self as Actor>::Context) {
+        if let Some(subtask) = self.subtasks.get_mut(&subtask_id) {
+            subtask.spec_ready = true;
+        }
+        self.start_processing(subtask_id, ctx)
+    }
+
+    fn start_processing(&mut self, subtask_id: String, ctx: &mut <Self as Actor>::Context) {
+        use gu_client::model::envman::{Command, ResourceFormat};
+
+        let (slot_id, spec) = match self.subtasks.get(&subtask_id) {
+            Some(subtask) if subtask.spec_ready && !subtask.running => {
+                (subtask.slot_id, subtask.spec.clone().unwrap())
+            }
+            _ => return,
+        };
+
+        let deployment = match self.peers.get(&slot_id) {
+            Some(slot) if slot.resource_ready => match slot.deployment.as_ref() {
+                Some(d) => d.clone(),
+                None => {
+                    log::error!("!!! deployment not ready !!!");
+                    return;
+                }
+            },
+            _ => {
+                log::debug!("subtask {} waits for resources", subtask_id);
                 return;
             }
         };
 
-        let output_file_name = self.spec.as_ref().unwrap().expected_output_file_name();
-        let output_path = format!("/golem/output/{}", output_file_name);
-        let output_uri = format!("{}/{}", self.output_uri, output_file_name);
-        let result_path = format!("{}/output", self.task.task_id());
+        let output_file_names = spec.expected_output_file_names();
+        let output_dir = self.output_uri.clone();
+        let result_path = result_path(self.task.task_id());
 
-        log::debug!("task {} output_file_name={}, output_path={}, output_uri={}", self.task.task_id(),
-            output_file_name, output_path, output_uri);
+        log::debug!(
+            "task {} output_file_names={:?}, output_uri={}",
+            self.task.task_id(),
+            output_file_names,
+            output_dir
+        );
 
-        self.state.mark_subtask_start();
+        self.subtasks.get_mut(&subtask_id).unwrap().running = true;
         log::info!(
-            "\n\nstarting blendering!!\n  subtask={}\n  out_file={}\n",
-            self.subtask_id.clone().unwrap(),
-            output_path,
+            "\n\nstarting blendering!!\n  subtask={}\n  peer={:?}\n  out_files={:?}\n",
+            subtask_id,
+            slot_id,
+            output_file_names,
         );
 
-        let compute = self.hub_session.new_blob().and_then(move |b| {
-            deployment.update(vec![
-                Command::Open,
-                Command::Wait,
-                Command::UploadFile {
-                    uri: b.uri(),
-                    file_path: output_path.clone(),
-                    format: ResourceFormat::Raw,
-                },
-                Command::UploadFile {
-                    uri: output_uri,
-                    file_path: output_path,
-                    format: ResourceFormat::Raw,
-                },
-            ])
-        });
+        let render = deployment
+            .update(vec![Command::Open, Command::Wait])
+            .map_err(|e| SubtaskFailure::Render(e.to_string()));
 
-        ctx.spawn(
+        // every output is uploaded on its own, so a missing file is reported by name
+        let upload_outputs = {
+            let output_file_names = output_file_names.clone();
+
+            move |_| {
+                future::join_all(output_file_names.into_iter().map(move |output_file_name| {
+                    deployment
+                        .update(vec![Command::UploadFile {
+                            uri: format!("{}/{}", output_dir, output_file_name),
+                            file_path: format!("/golem/output/{}", output_file_name),
+                            format: ResourceFormat::Raw,
+                        }])
+                        .map_err(move |e| {
+                            SubtaskFailure::MissingOutput(format!("{}: {}", output_file_name, e))
+                        })
+                }))
+            }
+        };
+
+        let compute = render.and_then(upload_outputs);
+
+        let started_at = Instant::now();
+        let slot_subtask_id = subtask_id.clone();
+        let compute = ctx.spawn(
             compute
                 .into_actor(self)
-                .map_err(|e, _, _| log::error!("\n\nblendering failed!!\n  err: {}", e))
-                .and_then(|r, act: &mut TaskWorker, _ctx| {
-                    log::info!(
-                        "\n\nblendering done!!\n  results in: {}\n  {:?}",
-                        result_path,
-                        r
-                    );
+                .then(move |r, act: &mut TaskWorker, ctx| match r {
+                    Ok(r) => {
+                        act.stop_watchdog(&subtask_id, ctx);
+                        workman::record_render(slot_id.node_id, started_at.elapsed());
+                        log::info!(
+                            "\n\nblendering done!!\n  results in: {}\n  {:?}",
+                            result_path,
+                            r
+                        );
 
-                    act.api
-                        .subtask_result(
-                            &act.node_id,
-                            act.subtask_id.as_ref().unwrap(),
-                            golem_gw_api::models::SubtaskResult::new(
-                                "succeeded".into(),
-                                result_path,
-                            ),
+                        actix::fut::Either::A(
+                            act.api
+                                .subtask_result(
+                                    &act.node_id,
+                                    &subtask_id,
+                                    golem_gw_api::models::SubtaskResult::new(
+                                        "succeeded".into(),
+                                        result_path,
+                                    ),
+                                )
+                                .map_err(|e| log::error!("fail send result: {}", e))
+                                .and_then(|_r| Ok(log::info!("sending results done")))
+                                .into_actor(act)
+                                .then(move |_, act: &mut TaskWorker, _| {
+                                    if let Some(subtask) = act.subtasks.get_mut(&subtask_id) {
+                                        subtask.result_sent = true;
+                                    }
+                                    fut::ok(())
+                                }),
                         )
-                        .map_err(|e| log::error!("fail send result: {}", e))
-                        .and_then(|_r| Ok(log::info!("sending results done")))
-                        .into_actor(act)
+                    }
+                    Err(failure) => {
+                        act.report_failure(subtask_id, failure, ctx);
+                        actix::fut::Either::B(fut::ok(()))
+                    }
                 }),
         );
+        if let Some(subtask) = self.subtasks.get_mut(&slot_subtask_id) {
+            subtask.compute = Some(compute);
+        }
     }
-}
 
-#[derive(Default, Debug)]
-struct State {
-    resource_ready: bool,
-    spec_ready: bool,
-}
+    /// Moves subtask which failed because of its peer to another peer. When it
+    /// can not be moved, sends failed subtask result to the gateway and asks
+    /// for the next subtask on the freed peer.
+    fn report_failure(
+        &mut self,
+        subtask_id: String,
+        failure: SubtaskFailure,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        log::error!("\n\nblendering failed!!\n  subtask={}\n  err: {}", subtask_id, failure);
+
+        if let (Some(slot_id), Some(kind)) = (
+            self.subtasks.get(&subtask_id).map(|subtask| subtask.slot_id),
+            failure.peer_failure(),
+        ) {
+            workman::record_failure(slot_id.node_id, kind);
+        }
+        if self.migrate_subtask(&subtask_id, &failure, ctx) {
+            return;
+        }
+
+        // peer of a subtask which could not be moved is already released
+        let slot_id = self
+            .finish_subtask(&subtask_id, ctx)
+            .filter(|slot_id| self.peers.contains_key(slot_id));
+        ctx.spawn(
+            self.send_failure(subtask_id, failure)
+                .into_actor(self)
+                .and_then(move |_r, act: &mut TaskWorker, _| match slot_id {
+                    Some(slot_id) => actix::fut::Either::A(
+                        act.want_next_subtask(slot_id).map_err(|_, _, _| ()),
+                    ),
+                    None => actix::fut::Either::B(fut::ok(())),
+                }),
+        );
+    }
 
-impl State {
-    #[inline]
-    fn is_ready(&self) -> bool {
-        self.resource_ready && self.spec_ready
+    /// Reports subtask which can not be bound to any peer as failed, once for
+    /// both its resource and its spec.
+    fn reject_unplaced(&mut self, subtask_id: String, ctx: &mut <Self as Actor>::Context) {
+        if self.unplaced.insert(subtask_id.clone()) {
+            self.report_failure(
+                subtask_id,
+                SubtaskFailure::Environment("no free peer".into()),
+                ctx,
+            );
+        }
     }
 
-    fn mark_subtask_start(&mut self) {
-        self.spec_ready = false;
+    /// Tags of deployments created for the task, used to find them after restart.
+    fn deployment_tags(&self) -> Vec<String> {
+        blender::deployment_tags(&self.node_id, self.hub_session.id(), self.task.task_id())
     }
-}
 
-impl Handler<DoSubTask> for TaskWorker {
-    type Result = ActorResponse<TaskWorker, (), gu_client::error::Error>;
+    /// Moves subtask which failed on its peer to another one, as long as
+    /// attempts are left and the deadline allows. The failed peer is released,
+    /// since its deployment can not be trusted anymore. Returns false when the
+    /// subtask has to be reported as failed.
+    fn migrate_subtask(
+        &mut self,
+        subtask_id: &str,
+        failure: &SubtaskFailure,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> bool {
+        if !failure.is_peer_fault() || self.drained.is_some() {
+            return false;
+        }
+        let can_retry = match self.subtasks.get(subtask_id) {
+            Some(subtask) => {
+                subtask.spec.is_some()
+                    && subtask.failed_peers.len() + 1 < MAX_SUBTASK_ATTEMPTS
+                    && self.time_left(subtask.deadline) > Duration::from_secs(0)
+            }
+            None => false,
+        };
+        if !can_retry {
+            return false;
+        }
 
-    fn handle(&mut self, msg: DoSubTask, ctx: &mut Self::Context) -> Self::Result {
+        let subtask = self.subtasks.get_mut(subtask_id).unwrap();
+        let failed_slot = subtask.slot_id;
+        subtask.failed_peers.push(failed_slot.node_id);
+        subtask.spec_ready = false;
+        subtask.running = false;
+        if let Some(compute) = subtask.compute.take() {
+            ctx.cancel_future(compute);
+        }
+        let exclude = subtask.failed_peers.clone();
+        log::warn!(
+            "subtask {} failed @ peer {:?}: {}; moving to another peer",
+            subtask_id,
+            failed_slot,
+            failure
+        );
+
+        if let Some(slot) = self.peers.get_mut(&failed_slot) {
+            slot.subtask_id = None;
+        }
+        self.release_slot(failed_slot);
+
+        let subtask_id = subtask_id.to_owned();
+        let reason = failure.to_string();
+        ctx.spawn(
+            self.create_deployment_with_retry(2, exclude)
+                .then(move |r, act: &mut TaskWorker, ctx| {
+                    match r {
+                        Ok(slot_id) => act.resume_subtask(subtask_id, slot_id, ctx),
+                        Err(()) => act.report_failure(
+                            subtask_id,
+                            SubtaskFailure::Environment(format!(
+                                "no peer to retry on after: {}",
+                                reason
+                            )),
+                            ctx,
+                        ),
+                    }
+                    fut::ok(())
+                }),
+        );
+        true
+    }
+
+    /// Continues moved subtask on a new peer: downloads resources, then uploads
+    /// spec and reruns it. The peer takes next subtask when this one is gone meanwhile.
+    fn resume_subtask(
+        &mut self,
+        subtask_id: String,
+        slot_id: SlotId,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let spec = match self.subtasks.get_mut(&subtask_id) {
+            Some(subtask) => {
+                subtask.slot_id = slot_id;
+                subtask.spec.clone().unwrap()
+            }
+            None => {
+                ctx.spawn(self.want_next_subtask(slot_id).map_err(|_, _, _| ()));
+                return;
+            }
+        };
+        if let Some(slot) = self.peers.get_mut(&slot_id) {
+            slot.subtask_id = Some(subtask_id.clone());
+        }
+        workman::assign_subtask(self.task.task_id(), slot_id, Some(&subtask_id));
+        log::info!("subtask {} moved to peer {:?}", subtask_id, slot_id);
+
+        // redeploy downloads resources on its own; each step reports its failure,
+        // so the subtask is prepared only after resources are downloaded
+        let download: Box<
+            dyn ActorFuture<Actor = TaskWorker, Item = (), Error = gu_client::error::Error>,
+        > = match self.required_image(slot_id, &spec) {
+            Ok(None) => {
+                let zip_uri = self.resource_uri.clone();
+                self.download_resource(slot_id, zip_uri)
+            }
+            _ => Box::new(fut::ok(())),
+        };
+        ctx.spawn(
+            download
+                .and_then(move |_, act: &mut TaskWorker, ctx| {
+                    act.prepare_subtask(slot_id, subtask_id, spec, ctx)
+                })
+                .map_err(|_, _, _| ()),
+        );
+    }
+
+    fn send_failure(
+        &self,
+        subtask_id: String,
+        failure: SubtaskFailure,
+    ) -> impl Future<Item = (), Error = ()> {
+        self.gateway.do_send(SubtaskFailed {
+            task_id: self.task.task_id().to_owned(),
+            subtask_id: subtask_id.clone(),
+            reason: failure.to_string(),
+        });
+
+        let result_path = result_path(self.task.task_id());
+        self.api
+            .subtask_result(
+                &self.node_id,
+                &subtask_id,
+                golem_gw_api::models::SubtaskResult::new("failed".into(), result_path)
+                    .with_reason(failure.to_string()),
+            )
+            .map_err(move |e| log::error!("fail send failure of subtask {}: {}", subtask_id, e))
+            .map(|_| ())
+    }
+
+    /// Subtasks which results were not sent to the gateway yet.
+    fn pending_subtasks(&self) -> Vec<String> {
+        self.subtasks
+            .iter()
+            .filter(|(_, subtask)| !subtask.result_sent)
+            .map(|(subtask_id, _)| subtask_id.clone())
+            .collect()
+    }
+
+    /// Reports subtasks still in progress as failed, frees peers and stops the worker.
+    fn finish_drain(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if let Some(check) = self.drain_check.take() {
+            ctx.cancel_future(check);
+        }
+
+        let pending = self.pending_subtasks();
+        let failures: Vec<_> = pending
+            .into_iter()
+            .map(|subtask_id| {
+                log::warn!("subtask {} not finished before shutdown", subtask_id);
+                if let Some(compute) = self
+                    .subtasks
+                    .get_mut(&subtask_id)
+                    .and_then(|subtask| subtask.compute.take())
+                {
+                    ctx.cancel_future(compute);
+                }
+                self.send_failure(subtask_id, SubtaskFailure::Shutdown)
+                    .then(|_| Ok::<(), ()>(()))
+            })
+            .collect();
+
+        ctx.spawn(
+            future::join_all(failures)
+                .into_actor(self)
+                .then(|_, act: &mut TaskWorker, _| {
+                    // drain completes only when peers are free, the system may stop right after
+                    let peers: Vec<SlotId> = act.peers.keys().cloned().collect();
+                    let released: Vec<_> = peers
+                        .into_iter()
+                        .map(|slot_id| act.free_slot(slot_id))
+                        .collect();
+                    future::join_all(released).into_actor(act)
+                })
+                .then(|_, act: &mut TaskWorker, ctx| {
+                    if let Some(drained) = act.drained.take() {
+                        let _ = drained.send(());
+                    }
+                    ctx.stop();
+                    fut::ok(())
+                }),
+        );
+    }
+
+    /// Image required by the subtask if it differs from the one deployed on the peer.
+    fn required_image(
+        &self,
+        slot_id: SlotId,
+        spec: &blender::BlenderSubtaskSpec,
+    ) -> Result<Option<blender::ImageSpec>, SubtaskFailure> {
+        let version = match spec.blender_version() {
+            Some(version) => version,
+            None => return Ok(None),
+        };
+
+        if self
+            .peers
+            .get(&slot_id)
+            .and_then(|slot| slot.image.as_ref())
+            .map(|image| image.blender_version == version)
+            .unwrap_or(false)
+        {
+            return Ok(None);
+        }
+
+        match self.images.find(self.docker, Some(version)) {
+            Some(image) => Ok(Some(image.clone())),
+            None => Err(SubtaskFailure::Environment(format!(
+                "no {} image for blender {}",
+                blender::env_type(self.docker),
+                version
+            ))),
+        }
+    }
+
+    /// Deploys image required by the subtask on its peer and uploads the subtask spec.
+    fn prepare_subtask(
+        &mut self,
+        slot_id: SlotId,
+        subtask_id: String,
+        subtask_spec: blender::BlenderSubtaskSpec,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Box<dyn ActorFuture<Actor = TaskWorker, Item = (), Error = gu_client::error::Error>> {
         use gu_client::model::envman::Command;
 
-        let mut subtask_spec: blender::BlenderSubtaskSpec =
-            blender::decode(msg.0.extra_data().clone()).unwrap();
+        let prepare: Box<
+            dyn ActorFuture<Actor = TaskWorker, Item = (), Error = gu_client::error::Error>,
+        > = match self.required_image(slot_id, &subtask_spec) {
+            Ok(None) => Box::new(fut::ok(())),
+            Ok(Some(image)) => self.redeploy(slot_id, &subtask_id, image),
+            Err(failure) => {
+                let err = gu_client::error::Error::Other(failure.to_string());
+                self.report_failure(subtask_id, failure, ctx);
+                return Box::new(fut::err(err));
+            }
+        };
 
-        subtask_spec.normalize_path();
-        log::info!("got subtask {}; {}", msg.0.subtask_id(), subtask_spec);
+        Box::new(prepare.and_then(move |_, act: &mut TaskWorker, ctx| {
+            let deployment = match act.peers.get(&slot_id).and_then(|slot| slot.deployment.as_ref()) {
+                Some(d) => d,
+                None => {
+                    act.report_failure(
+                        subtask_id,
+                        SubtaskFailure::Deployment("deployment not ready".into()),
+                        ctx,
+                    );
+                    return actix::fut::Either::B(fut::err(gu_client::error::Error::Other(
+                        "deployment not ready".into(),
+                    )));
+                }
+            };
+
+            let upload_spec = deployment.update(vec![Command::WriteFile {
+                file_path: "golem/resources/spec.json".to_string(),
+                content: serde_json::to_string(&subtask_spec).unwrap(),
+            }]);
+
+            let failed_subtask_id = subtask_id.clone();
+            actix::fut::Either::A(
+                upload_spec
+                    .into_actor(act)
+                    .map_err(move |e, act: &mut TaskWorker, ctx| {
+                        act.report_failure(
+                            failed_subtask_id,
+                            SubtaskFailure::SpecUpload(e.to_string()),
+                            ctx,
+                        );
+                        e
+                    })
+                    .and_then(move |_r, act: &mut TaskWorker, ctx| {
+                        act.spec_ready(subtask_id, ctx);
+                        fut::ok(())
+                    }),
+            )
+        }))
+    }
+
+    /// Replaces deployment on the reserved peer; resources are downloaded again.
+    /// Failures are reported against the subtask which required the new image.
+    fn redeploy(
+        &mut self,
+        slot_id: SlotId,
+        subtask_id: &str,
+        image: blender::ImageSpec,
+    ) -> Box<dyn ActorFuture<Actor = TaskWorker, Item = (), Error = gu_client::error::Error>> {
+        let slot = match self.peers.get_mut(&slot_id) {
+            Some(slot) => slot,
+            None => {
+                return Box::new(fut::err(gu_client::error::Error::Other(
+                    "peer not reserved".into(),
+                )))
+            }
+        };
+
+        log::info!(
+            "switching to blender {} @ peer {:?}",
+            image.blender_version,
+            slot_id
+        );
+        if let Some(deployment) = slot.deployment.take() {
+            Arbiter::spawn(blender::destroy_deployment(deployment));
+        }
+        slot.resource_ready = false;
+
+        let failed_subtask_id = subtask_id.to_string();
+        Box::new(
+            blender::blender_deployment_spec(
+                self.hub_session.peer(slot_id.node_id),
+                &image,
+                self.deployment_tags(),
+            )
+            .into_actor(self)
+            .map_err(move |e, act: &mut TaskWorker, ctx| {
+                act.report_failure(
+                    failed_subtask_id,
+                    SubtaskFailure::Deployment(e.to_string()),
+                    ctx,
+                );
+                e
+            })
+            .and_then(move |deployment, act: &mut TaskWorker, _| {
+                act.gateway.do_send(EnvironmentAvailable);
+                if let Some(slot) = act.peers.get_mut(&slot_id) {
+                    slot.deployment = Some(deployment);
+                    slot.image = Some(image);
+                }
+                let zip_uri = act.resource_uri.clone();
+                act.download_resource(slot_id, zip_uri)
+            }),
+        )
+    }
 
-        self.spec = Some(subtask_spec.clone());
-        self.subtask_id = Some(msg.0.subtask_id().clone());
+    fn download_resource(
+        &self,
+        slot_id: SlotId,
+        zip_uri: Option<String>,
+    ) -> Box<dyn ActorFuture<Actor = TaskWorker, Item = (), Error = gu_client::error::Error>> {
+        use gu_client::model::envman::{Command, ResourceFormat};
 
-        let deployment = match self.deployment.as_ref() {
+        let zip_uri = match zip_uri {
+            Some(zip_uri) => zip_uri,
+            None => return Box::new(fut::ok(())),
+        };
+        let deployment = match self.peers.get(&slot_id).and_then(|slot| slot.deployment.as_ref()) {
             Some(d) => d,
             None => {
-                return ActorResponse::reply(Err(gu_client::error::Error::Other(
+                return Box::new(fut::err(gu_client::error::Error::Other(
                     "deployment not ready".into(),
                 )));
             }
         };
 
-        self.cnt.subtasks_cnt += 1;
+        Box::new(
+            deployment
+                .update(vec![Command::DownloadFile {
+                    uri: zip_uri,
+                    file_path: "/golem/resources/gu.zip".to_string(),
+                    format: ResourceFormat::Raw,
+                }])
+                .into_actor(self)
+                .map_err(move |e, act: &mut TaskWorker, ctx| {
+                    let subtask_id = act
+                        .peers
+                        .get(&slot_id)
+                        .and_then(|slot| slot.subtask_id.clone());
+                    if let Some(subtask_id) = subtask_id {
+                        act.report_failure(subtask_id, SubtaskFailure::Download(e.to_string()), ctx);
+                    }
+                    e
+                })
+                .and_then(move |r, act: &mut TaskWorker, ctx| {
+                    log::info!("resource downloaded @ peer {:?}: {:?}", slot_id, r);
+                    act.resource_ready(slot_id, ctx);
+                    fut::ok(())
+                }),
+        )
+    }
+
+    /// Asks the gateway for a subtask to compute on the given peer.
+    fn want_next_subtask(
+        &self,
+        slot_id: SlotId,
+    ) -> Box<dyn ActorFuture<Actor = TaskWorker, Item = (), Error = gu_client::error::Error>> {
+        if self.drained.is_some() {
+            log::debug!("worker draining, peer {:?} left idle", slot_id);
+            return Box::new(fut::ok(()));
+        }
+
+        Box::new(
+            self.api
+                .want_to_compute_task(&self.node_id, self.task.task_id())
+                .into_actor(self)
+                .and_then(move |m, _, _| {
+                    fut::ok(log::info!(
+                        "want to compute (next) task send for peer {:?}: {:?}",
+                        slot_id,
+                        m
+                    ))
+                })
+                .map_err(move |e, act, ctx| {
+                    let msg = format!("{:?}", e);
+                    let task_not_found = format!("{} not found", act.task.task_id());
+                    if msg.contains(task_not_found.as_str()) {
+                        // TODO: check if requestor sends NO_MORE_SUBTASKS to gw and pass it as an event
+                        log::info!("task {} has finished", act.task.task_id());
+                        act.release_slot(slot_id);
+                        if act.peers.is_empty() {
+                            act.gateway.do_send(TaskFinished {
+                                task_id: act.task.task_id().to_owned(),
+                            });
+                            ctx.stop();
+                        }
+                        gu_client::error::Error::Other("task finshed".into())
+                    } else {
+                        log::error!("want to compute (next) task failed: {:?}", e);
+                        gu_client::error::Error::Other(e.to_string())
+                    }
+                }),
+        )
+    }
+}
+
+/// Directory holding subtask outputs, relative to the gateway DAV root.
+fn result_path(task_id: &str) -> String {
+    format!("{}/output", task_id)
+}
+
+impl Handler<DoSubTask> for TaskWorker {
+    type Result = ActorResponse<TaskWorker, (), gu_client::error::Error>;
+
+    fn handle(&mut self, msg: DoSubTask, ctx: &mut Self::Context) -> Self::Result {
+        let subtask_id = msg.0.subtask_id().clone();
+        let slot_id = match self.bind_subtask(&subtask_id) {
+            Some(slot_id) => slot_id,
+            None => {
+                log::warn!("no free peer for subtask {}", subtask_id);
+                self.reject_unplaced(subtask_id, ctx);
+                return ActorResponse::reply(Err(gu_client::error::Error::Other(
+                    "no free peer".into(),
+                )));
+            }
+        };
+        if let Some(subtask) = self.subtasks.get_mut(&subtask_id) {
+            subtask.deadline = (*msg.0.deadline()) as u64;
+        }
+        self.start_watchdog(&subtask_id, (*msg.0.deadline()) as u64, ctx);
+
+        let mut subtask_spec: blender::BlenderSubtaskSpec =
+            match blender::decode(msg.0.extra_data().clone()) {
+                Ok(spec) => spec,
+                Err(e) => {
+                    let err = gu_client::error::Error::Other(e.to_string());
+                    self.report_failure(subtask_id, SubtaskFailure::InvalidSpec(e.to_string()), ctx);
+                    return ActorResponse::reply(Err(err));
+                }
+            };
+
+        subtask_spec.normalize_path();
+        log::info!("got subtask {} @ peer {:?}; {}", subtask_id, slot_id, subtask_spec);
+
+        self.subtasks.get_mut(&subtask_id).unwrap().spec = Some(subtask_spec.clone());
+
         let _ = ctx.spawn(
             self.api
-                .confirm_subtask(&self.node_id, self.subtask_id.as_ref().unwrap())
+                .confirm_subtask(&self.node_id, &subtask_id)
                 .into_actor(self)
-                .map_err(|e, act, _| {
-                    log::warn!("subtask {:?} confirmation failure: {}", act.subtask_id, e)
+                .map_err({
+                    let subtask_id = subtask_id.clone();
+                    move |e, _, _| log::warn!("subtask {} confirmation failure: {}", subtask_id, e)
                 })
-                .and_then(|_r, act, _| {
-                    log::info!("subtask {:?} confirmed", act.subtask_id);
-                    fut::ok(())
+                .and_then({
+                    let subtask_id = subtask_id.clone();
+                    move |_r, _, _| {
+                        log::info!("subtask {} confirmed", subtask_id);
+                        fut::ok(())
+                    }
                 }),
         );
 
-        let upload_spec = deployment.update(vec![Command::WriteFile {
-            file_path: "golem/resources/spec.json".to_string(),
-            content: serde_json::to_string(&subtask_spec).unwrap(),
-        }]);
-
-        ActorResponse::r#async(upload_spec.into_actor(self).and_then(
-            |_r, act: &mut TaskWorker, ctx| {
-                act.spec_ready(ctx);
-                fut::ok(())
-            },
-        ))
+        ActorResponse::r#async(self.prepare_subtask(slot_id, subtask_id, subtask_spec, ctx))
     }
 }
 
@@ -230,210 +978,325 @@ impl Handler<DoResource> for TaskWorker {
     type Result = ActorResponse<TaskWorker, (), gu_client::error::Error>;
 
     fn handle(&mut self, msg: DoResource, ctx: &mut Self::Context) -> Self::Result {
-        use gu_client::model::envman::{Command, ResourceFormat};
+        let r = &msg.0;
+        let slot_id = match self.bind_subtask(r.subtask_id()) {
+            Some(slot_id) => slot_id,
+            None => {
+                log::warn!("no free peer for resource of subtask {}", r.subtask_id());
+                self.reject_unplaced(r.subtask_id().clone(), ctx);
+                return ActorResponse::reply(Err(gu_client::error::Error::Other(
+                    "no free peer".into(),
+                )));
+            }
+        };
 
-        if self.state.resource_ready {
+        // resources are shared by all subtasks of the task, every peer downloads them once
+        if self.peers.get(&slot_id).map(|slot| slot.resource_ready).unwrap_or(false) {
+            self.resource_ready(slot_id, ctx);
             return ActorResponse::reply(Ok(()));
         }
 
-        let r = &msg.0;
         let zip_uri = format!("{}/{}/{}", self.dav_url, r.path(), r.subtask_id());
         let task_uri = format!("{}/{}", self.dav_url, r.res_id());
 
-        self.subtask_id = Some(r.subtask_id().clone());
         log::info!("got resource for subtask {}, zip={}, task={}", r.subtask_id(), zip_uri, task_uri);
 
-        let deployment = match self.deployment.as_ref() {
-            Some(d) => d,
-            None => {
-                return ActorResponse::reply(Err(gu_client::error::Error::Other(
-                    "deployment not ready".into(),
-                )));
-            }
-        };
-
-        let upload_zip = deployment.update(vec![Command::DownloadFile {
-            uri: zip_uri.clone(),
-            file_path: "/golem/resources/gu.zip".to_string(),
-            format: ResourceFormat::Raw,
-        }]);
-
-        let create_output = dav::DavPath::new(task_uri.parse().unwrap())
-            .mkdir("output")
-            .into_actor(self)
-            .map_err(|e, _, _| log::warn!("unable to create output dir at {:?}", e))
-            .and_then(|r, act: &mut TaskWorker, _| {
-                act.output_uri = r.to_string();
-                log::debug!("output path={}", act.output_uri);
-                fut::ok(())
-            });
+        if self.resource_uri.is_none() {
+            let create_output = dav::DavPath::new(task_uri.parse().unwrap())
+                .mkdir("output")
+                .into_actor(self)
+                .map_err(|e, _, _| log::warn!("unable to create output dir at {:?}", e))
+                .and_then(|r, act: &mut TaskWorker, _| {
+                    act.output_uri = r.to_string();
+                    log::debug!("output path={}", act.output_uri);
+                    fut::ok(())
+                });
 
-        let _ = ctx.spawn(create_output);
+            let _ = ctx.spawn(create_output);
+        }
+        self.resource_uri = Some(zip_uri.clone());
 
         log::info!("got resource; path: {}", r.path());
-        ActorResponse::r#async(upload_zip.into_actor(self).and_then(
-            move |r, act: &mut TaskWorker, ctx| {
-                act.resource_ready(ctx);
-                fut::ok(log::info!(
-                    "resource downloaded for {}: {:?}",
-                    act.subtask_id.as_ref().unwrap_or(&"unknown subtask".into()),
-                    r
-                ))
-            },
-        ))
+        ActorResponse::r#async(self.download_resource(slot_id, Some(zip_uri)))
     }
 }
 
 impl Handler<DoSubtaskVerification> for TaskWorker {
     type Result = ActorResponse<TaskWorker, (), gu_client::error::Error>;
 
-    fn handle(&mut self, msg: DoSubtaskVerification, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: DoSubtaskVerification, ctx: &mut Self::Context) -> Self::Result {
         let s_v = &msg.0;
         let subtask_id = s_v.subtask_id();
 
-        if self.subtask_id.as_ref().unwrap() != subtask_id {
-            log::warn!(
-                "verification of {} but for {} needed",
-                subtask_id,
-                self.subtask_id.as_ref().unwrap()
-            );
-        }
+        let slot_id = match self.finish_subtask(subtask_id, ctx) {
+            Some(slot_id) => slot_id,
+            None => {
+                log::warn!("verification of unknown subtask {}", subtask_id);
+                return ActorResponse::reply(Ok(()));
+            }
+        };
 
         if s_v.verification_result() != "OK" {
             let reason = s_v
                 .reason()
                 .expect("negative verification should have reason");
             log::warn!("verification of {} failure : {:?}", subtask_id, reason);
+            workman::record_failure(slot_id.node_id, workman::PeerFailure::Verification);
+            ctx.spawn(self.want_next_subtask(slot_id).map_err(|_, _, _| ()));
             return ActorResponse::reply(Err(gu_client::error::Error::Other(format!(
                 "subtask {} result not accepted: {}",
                 subtask_id, reason
             ))));
         }
 
-        self.cnt.subtasks_done_cnt += 1;
-
         log::info!("subtask {} verified successfully", s_v.subtask_id());
+        ActorResponse::r#async(self.want_next_subtask(slot_id))
+    }
+}
+
+/// Stops taking new subtasks and waits up to `grace_period` for results of
+/// running ones. Worker stops when done.
+pub struct Drain {
+    pub grace_period: Duration,
+}
+
+impl Message for Drain {
+    type Result = Result<(), Error>;
+}
+
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+impl Handler<Drain> for TaskWorker {
+    type Result = ActorResponse<TaskWorker, (), Error>;
+
+    fn handle(&mut self, msg: Drain, ctx: &mut Self::Context) -> Self::Result {
+        let (tx, rx) = oneshot::channel();
+        let drain_until = Instant::now() + msg.grace_period;
+        log::info!(
+            "draining worker of task {}, {} subtasks pending",
+            self.task.task_id(),
+            self.pending_subtasks().len()
+        );
+
+        self.drained = Some(tx);
+        self.drain_check = Some(ctx.run_interval(DRAIN_CHECK_INTERVAL, move |act, ctx| {
+            if act.pending_subtasks().is_empty() || Instant::now() >= drain_until {
+                act.finish_drain(ctx)
+            }
+        }));
+
         ActorResponse::r#async(
-            self.api
-                .want_to_compute_task(&self.node_id, self.task.task_id())
-                .into_actor(self)
-                .and_then(|m, _, _| {
-                    fut::ok(log::info!("want to compute (next) task send: {:?}", m))
-                })
-                .map_err(|e, act, _| {
-                    let msg = format!("{:?}", e);
-                    let task_not_found = format!("{} not found", act.task.task_id());
-                    if msg.contains(task_not_found.as_str()) {
-                        // TODO: clean-up after last subtask, use task deadline
-                        // TODO: check if requestor sends NO_MORE_SUBTASKS to gw and pass it as an event
-                        log::info!("task {} has finished", act.task.task_id());
-                        gu_client::error::Error::Other("task finshed".into())
-                    } else {
-                        log::error!("want to compute (next) task failed: {:?}", e);
-                        gu_client::error::Error::Other(e.to_string())
-                    }
-                }),
+            rx.map_err(|_| Error::Other("worker stopped before drain".into()))
+                .into_actor(self),
         )
     }
 }
 
+impl Handler<StopTask> for TaskWorker {
+    type Result = ();
+
+    fn handle(&mut self, _msg: StopTask, ctx: &mut Self::Context) -> Self::Result {
+        log::info!("stopping worker of task {}", self.task.task_id());
+        ctx.stop();
+    }
+}
+
 impl TaskWorker {
-    fn create_deployment(&self) -> Box<dyn ActorFuture<Actor = TaskWorker, Item = (), Error = ()>> {
+    /// Reserves a peer and deploys blender on it; the peer becomes a new slot.
+    fn create_deployment(
+        &self,
+        exclude: Vec<NodeId>,
+    ) -> Box<dyn ActorFuture<Actor = TaskWorker, Item = SlotId, Error = ()>> {
+        let image = match self.images.find(self.docker, None) {
+            Some(image) => image.clone(),
+            None => {
+                self.gateway.do_send(EnvironmentUnavailable {
+                    peer_id: None,
+                    docker: self.docker,
+                    error: "no image configured".into(),
+                });
+                return Box::new(fut::err(()));
+            }
+        };
+
         Box::new(
             workman::reserve_for_session(
                 self.hub_session.id(),
                 self.task.task_id(),
+                blender::owner_tag(&self.node_id, self.hub_session.id()),
                 (*self.task.deadline()) as u64,
+                self.peer_selection,
+                self.slots_per_peer,
+                exclude,
             )
             .map_err(|_| {
                 /*TODO*/
                 ()
             })
             .into_actor(self)
-            .and_then(|peer_id, act: &mut TaskWorker, _| {
-                act.peer_id = Some(peer_id);
+            .and_then(move |slot_id, act: &mut TaskWorker, _| {
                 act.hub_session
-                    .add_peers(vec![peer_id])
+                    .add_peers(vec![slot_id.node_id])
                     .into_actor(act)
-                    .map_err(|e, act, _| {
-                        log::error!("fail to add peer {:?}: {}", act.peer_id.unwrap(), e)
-                    })
-                    .and_then(|_, act: &mut TaskWorker, _| {
+                    .map_err(move |e, _, _| log::error!("fail to add peer {:?}: {}", slot_id, e))
+                    .and_then(move |_, act: &mut TaskWorker, _| {
                         blender::blender_deployment_spec(
-                            act.hub_session.peer(act.peer_id.unwrap()),
-                            true,
+                            act.hub_session.peer(slot_id.node_id),
+                            &image,
+                            act.deployment_tags(),
                         )
-                        .into_actor(act)
-                        .map_err(|e, act, _| {
-                            log::warn!(
-                                "unable to create deployment @ peer: {:?}, err: {}",
-                                act.peer_id.unwrap(),
-                                e
-                            )
-                        })
-                        .and_then(|deployment, act: &mut TaskWorker, _| {
-                            act.deployment = Some(deployment);
-                            fut::ok(())
-                        })
+                            .into_actor(act)
+                            .map_err(move |e, act, _| {
+                                log::warn!(
+                                    "unable to create {} deployment @ peer: {:?}, err: {}",
+                                    blender::env_type(act.docker),
+                                    slot_id,
+                                    e
+                                );
+                                workman::record_failure(slot_id.node_id, workman::PeerFailure::Deployment);
+                                act.gateway.do_send(EnvironmentUnavailable {
+                                    peer_id: Some(slot_id.node_id),
+                                    docker: act.docker,
+                                    error: e.to_string(),
+                                })
+                            })
+                            .and_then(move |deployment, act: &mut TaskWorker, _| {
+                                act.gateway.do_send(EnvironmentAvailable);
+                                act.peers.insert(
+                                    slot_id,
+                                    PeerSlot {
+                                        deployment: Some(deployment),
+                                        image: Some(image),
+                                        resource_ready: false,
+                                        subtask_id: None,
+                                    },
+                                );
+                                fut::ok(slot_id)
+                            })
+                    })
+                    .then(move |r, act: &mut TaskWorker, _| {
+                        if r.is_err() {
+                            workman::release(act.task.task_id(), slot_id);
+                        }
+                        fut::result(r)
                     })
             }),
         )
     }
 
+    /// Destroys deployment of the peer and returns its reservation.
+    fn release_slot(&mut self, slot_id: SlotId) {
+        Arbiter::spawn(self.free_slot(slot_id));
+    }
+
+    /// Destroys deployment of the slot, then releases its reservation.
+    fn free_slot(&mut self, slot_id: SlotId) -> Box<dyn Future<Item = (), Error = ()>> {
+        let slot = match self.peers.remove(&slot_id) {
+            Some(slot) => slot,
+            None => return Box::new(future::ok(())),
+        };
+        log::info!("releasing peer {:?} of task {}", slot_id, self.task.task_id());
+
+        let destroy = match slot.deployment {
+            Some(deployment) => future::Either::A(blender::destroy_deployment(deployment)),
+            None => future::Either::B(future::ok(())),
+        };
+        let task_id = self.task.task_id().to_owned();
+        Box::new(destroy.then(move |_| workman::release_wait(&task_id, slot_id)))
+    }
+
     fn create_deployment_with_retry(
         &self,
         retry_cnt: u32,
-    ) -> Box<dyn ActorFuture<Actor = TaskWorker, Item = (), Error = ()>> {
-        Box::new(self.create_deployment().then(move |r, act, _| match r {
+        exclude: Vec<NodeId>,
+    ) -> Box<dyn ActorFuture<Actor = TaskWorker, Item = SlotId, Error = ()>> {
+        Box::new(self.create_deployment(exclude.clone()).then(move |r, act, _| match r {
             Ok(v) => actix::fut::Either::A(fut::ok(v)),
             Err(e) => {
                 if retry_cnt > 0 {
-                    actix::fut::Either::B(act.create_deployment_with_retry(retry_cnt - 1))
+                    actix::fut::Either::B(act.create_deployment_with_retry(retry_cnt - 1, exclude))
                 } else {
-                    act.cnt.subtasks_fail_cnt += 1;
                     actix::fut::Either::A(fut::err(e))
                 }
             }
         }))
     }
+
+    /// Opens a new peer slot and asks for the first subtask to compute on it.
+    fn add_slot(&self) -> Box<dyn ActorFuture<Actor = TaskWorker, Item = (), Error = ()>> {
+        Box::new(
+            self.create_deployment_with_retry(5, Vec::new())
+                .and_then(|slot_id, act: &mut TaskWorker, _| {
+                    log::info!(
+                        "peer {:?} ready for task {} ({} slots)",
+                        slot_id,
+                        act.task.task_id(),
+                        act.peers.len()
+                    );
+                    act.want_next_subtask(slot_id).map_err(|e, _, _| {
+                        log::error!("want to compute (first) task failed: {:?}", e)
+                    })
+                }),
+        )
+    }
 }
 
 impl Actor for TaskWorker {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        let get_subtask = self
-            .api
-            .want_to_compute_task(&self.node_id, self.task.task_id())
+        // gateway leaves deployments of restored task to its worker; they are
+        // reaped before new ones, tagged the same way, are created. Reservations
+        // restored by WorkMan are dropped with them, slots are reserved anew.
+        workman::release_task(self.task.task_id());
+        ctx.spawn(
+            blender::reap_task_deployments(
+                self.hub_session.clone(),
+                &self.node_id,
+                self.task.task_id(),
+            )
+            .then(|r| {
+                if let Err(e) = r {
+                    log::warn!("unable to reap deployments of previous run: {}", e);
+                }
+                Ok::<(), ()>(())
+            })
             .into_actor(self)
-            .and_then(|m, _, _| fut::ok(log::info!("want to compute (first) task send: {:?}", m)))
-            .map_err(|e, _, _| log::error!("want to compute (first) task failed: {:?}", e));
+            .map(|_, act: &mut TaskWorker, ctx| {
+                for _ in 0..act.max_slots {
+                    ctx.spawn(act.add_slot());
+                }
+            }),
+        );
 
-        ctx.wait(
-            joinact::join_act_fut(get_subtask, self.create_deployment_with_retry(5))
-                .and_then(|_, _, _| fut::ok(())),
-        )
+        // no subtasks come after the task deadline, idle slots would wait forever
+        let task_deadline = UNIX_EPOCH + Duration::from_secs((*self.task.deadline()) as u64);
+        let until_deadline = task_deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        ctx.run_later(until_deadline, |act, ctx| {
+            log::info!("task {} reached its deadline", act.task.task_id());
+            ctx.stop();
+        });
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        let peers: Vec<SlotId> = self.peers.keys().cloned().collect();
+        for slot_id in peers {
+            self.release_slot(slot_id);
+        }
+        self.gateway.do_send(WorkerStopped {
+            task_id: self.task.task_id().to_owned(),
+        });
     }
 }
 
-use super::error::Error;
-use super::gateway::{Stats, StatsData};
-use crate::workman::reserve_for_session;
-
-impl Handler<Stats> for TaskWorker {
-    type Result = Result<StatsData, Error>;
-
-    fn handle(&mut self, msg: Stats, ctx: &mut Self::Context) -> Self::Result {
-        let result = StatsData {
-            tasks: 0,
-            subtasks: self.cnt.subtasks_cnt,
-            subtasks_done: self.cnt.subtasks_done_cnt,
-            fails: self.cnt.subtasks_fail_cnt,
-        };
-        self.cnt.subtasks_cnt = 0;
-        self.cnt.subtasks_done_cnt = 0;
-        self.cnt.subtasks_fail_cnt = 0;
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        Ok(result)
+    #[test]
+    fn test_result_path() {
+        assert_eq!(result_path("9a2f"), "9a2f/output");
     }
 }
@@ -1,30 +1,87 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use actix::prelude::*;
 use actix::Context;
+use diesel::SqliteConnection;
 use failure::*;
-use futures::prelude::*;
+use futures::{future, prelude::*};
 use gu_client::{r#async::HubConnection, NodeId};
 use rand::Rng as _;
+use serde_derive::*;
+
+use super::{blender, model};
 
 #[derive(Debug, Fail)]
 #[fail(display = "no free node")]
 pub struct NoFreeNode;
 
+/// Capacity slot of a peer. A task may hold several slots of the same peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SlotId {
+    pub node_id: NodeId,
+    pub index: u32,
+}
+
 #[derive(Debug)]
 struct Reservation {
+    index: u32,
     task_id: String,
+    subtask_id: Option<String>,
     reserved_until: SystemTime,
+    /// Owner tag of deployments created for the reservation.
+    owner: Option<String>,
 }
 
 impl Reservation {
-    fn new(task_id: String, deadline: u64) -> Reservation {
+    fn new(index: u32, task_id: String, deadline: u64, owner: Option<String>) -> Reservation {
         let reserved_until = UNIX_EPOCH + Duration::from_secs(deadline);
 
         Reservation {
+            index,
             task_id,
+            subtask_id: None,
             reserved_until,
+            owner,
+        }
+    }
+
+    /// Tags every deployment created for the reservation has.
+    fn deployment_tags(&self) -> Vec<String> {
+        self.owner
+            .iter()
+            .cloned()
+            .chain(std::iter::once(blender::task_tag(&self.task_id)))
+            .collect()
+    }
+
+    fn from_model(r: model::PeerReservation) -> Result<(NodeId, Reservation), String> {
+        let node_id = r
+            .node_id
+            .parse()
+            .map_err(|e| format!("invalid node id {}: {:?}", r.node_id, e))?;
+        let reserved_until = UNIX_EPOCH + Duration::from_secs(r.reserved_until.timestamp().max(0) as u64);
+
+        Ok((
+            node_id,
+            Reservation {
+                index: r.slot as u32,
+                task_id: r.task_id,
+                subtask_id: r.subtask_id,
+                reserved_until,
+                owner: r.owner,
+            },
+        ))
+    }
+
+    fn to_model(&self, node_id: NodeId) -> model::PeerReservation {
+        model::PeerReservation {
+            node_id: node_hex(node_id),
+            slot: self.index as i32,
+            task_id: self.task_id.clone(),
+            subtask_id: self.subtask_id.clone(),
+            reserved_until: naive_time(self.reserved_until),
+            owner: self.owner.clone(),
         }
     }
 
@@ -35,9 +92,52 @@ impl Reservation {
     }
 }
 
+fn node_hex(node_id: NodeId) -> String {
+    format!("{:x}", node_id)
+}
+
+fn naive_time(t: SystemTime) -> chrono::NaiveDateTime {
+    let secs = t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    chrono::NaiveDateTime::from_timestamp(secs as i64, 0)
+}
+
+/// Reservations holding capacity slots of a single peer.
+#[derive(Debug, Default)]
+struct PeerSlots {
+    reservations: Vec<Reservation>,
+}
+
+impl PeerSlots {
+    fn used(&self) -> usize {
+        self.reservations.iter().filter(|r| r.is_valid()).count()
+    }
+
+    /// Lowest slot index not held by a valid reservation.
+    fn free_index(&self) -> u32 {
+        (0..)
+            .find(|&index| !self.reservations.iter().any(|r| r.is_valid() && r.index == index))
+            .unwrap()
+    }
+
+    /// Releases the slot, as long as it is held by the task.
+    fn release(&mut self, index: u32, task_id: &str) -> Option<Reservation> {
+        let pos = self
+            .reservations
+            .iter()
+            .position(|r| r.index == index && r.task_id == task_id)?;
+        Some(self.reservations.remove(pos))
+    }
+}
+
 pub struct WorkMan {
     connection: HubConnection,
-    reservations: HashMap<NodeId, Reservation>,
+    reservations: HashMap<NodeId, PeerSlots>,
+    hardware: HashMap<NodeId, PeerHardware>,
+    selectors: HashMap<u64, (SelectionStrategy, Box<dyn PeerSelector>)>,
+    history: HashMap<NodeId, PeerHistory>,
+    /// Reservations are stored, so peers still busy after restart are not double-booked.
+    /// They are kept in memory only when the database is not available.
+    db: Option<SqliteConnection>,
 }
 
 impl Default for WorkMan {
@@ -47,20 +147,579 @@ impl Default for WorkMan {
         WorkMan {
             connection,
             reservations,
+            hardware: HashMap::new(),
+            selectors: HashMap::new(),
+            history: HashMap::new(),
+            db: model::establish_connection()
+                .map_err(|e| log::error!("reservations will not be persisted: {}", e))
+                .ok(),
+        }
+    }
+}
+
+/// Free peer considered for a reservation.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub node_id: NodeId,
+    /// Read only for strategies which need it.
+    pub hardware: Option<PeerHardware>,
+    pub last_used: Option<SystemTime>,
+    /// Subtasks rendered per second, when the peer rendered anything yet.
+    pub throughput: Option<f64>,
+}
+
+pub trait PeerSelector {
+    fn select(&mut self, candidates: &[Candidate]) -> Option<NodeId>;
+}
+
+/// Peer selection strategy of a hub session.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SelectionStrategy {
+    Random,
+    RoundRobin,
+    LeastRecentlyUsed,
+    BestThroughput,
+    MostFreeMemory,
+}
+
+impl Default for SelectionStrategy {
+    fn default() -> Self {
+        SelectionStrategy::Random
+    }
+}
+
+impl SelectionStrategy {
+    pub fn selector(self) -> Box<dyn PeerSelector> {
+        match self {
+            SelectionStrategy::Random => Box::new(RandomSelector),
+            SelectionStrategy::RoundRobin => Box::new(RoundRobinSelector::default()),
+            SelectionStrategy::LeastRecentlyUsed => Box::new(LeastRecentlyUsedSelector),
+            SelectionStrategy::BestThroughput => Box::new(BestThroughputSelector),
+            SelectionStrategy::MostFreeMemory => Box::new(MostFreeMemorySelector),
+        }
+    }
+
+    fn needs_hardware(self) -> bool {
+        self == SelectionStrategy::MostFreeMemory
+    }
+}
+
+pub struct RandomSelector;
+
+impl PeerSelector for RandomSelector {
+    fn select(&mut self, candidates: &[Candidate]) -> Option<NodeId> {
+        rand::thread_rng().choose(candidates).map(|c| c.node_id)
+    }
+}
+
+#[derive(Default)]
+pub struct RoundRobinSelector {
+    next: usize,
+}
+
+impl PeerSelector for RoundRobinSelector {
+    fn select(&mut self, candidates: &[Candidate]) -> Option<NodeId> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let it = candidates[self.next % candidates.len()].node_id;
+        self.next = self.next.wrapping_add(1);
+        Some(it)
+    }
+}
+
+/// Prefers peers never used, then the ones used longest ago.
+pub struct LeastRecentlyUsedSelector;
+
+impl PeerSelector for LeastRecentlyUsedSelector {
+    fn select(&mut self, candidates: &[Candidate]) -> Option<NodeId> {
+        candidates
+            .iter()
+            .min_by_key(|c| c.last_used)
+            .map(|c| c.node_id)
+    }
+}
+
+/// Prefers peers with the best render throughput. Peers without history are
+/// tried first, so every peer gets measured.
+pub struct BestThroughputSelector;
+
+impl PeerSelector for BestThroughputSelector {
+    fn select(&mut self, candidates: &[Candidate]) -> Option<NodeId> {
+        candidates
+            .iter()
+            .max_by(|a, b| {
+                let throughput = |c: &Candidate| c.throughput.unwrap_or(std::f64::INFINITY);
+                throughput(a)
+                    .partial_cmp(&throughput(b))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .map(|c| c.node_id)
+    }
+}
+
+/// Prefers peers with the most free memory.
+pub struct MostFreeMemorySelector;
+
+impl PeerSelector for MostFreeMemorySelector {
+    fn select(&mut self, candidates: &[Candidate]) -> Option<NodeId> {
+        candidates
+            .iter()
+            .max_by_key(|c| c.hardware.map(|hw| hw.free_memory).unwrap_or(0))
+            .map(|c| c.node_id)
+    }
+}
+
+/// Failure attributed to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerFailure {
+    /// Blender deployment could not be created.
+    Deployment,
+    /// Subtask failed or timed out while computed on the peer.
+    Render,
+    /// Gateway rejected result computed by the peer.
+    Verification,
+}
+
+/// Consecutive failures after which a peer is quarantined.
+const QUARANTINE_AFTER: u32 = 3;
+const QUARANTINE_PERIOD: Duration = Duration::from_secs(600);
+
+/// Past use of a peer.
+#[derive(Debug, Default)]
+struct PeerHistory {
+    last_used: Option<SystemTime>,
+    subtasks_done: u32,
+    render_time: Duration,
+    deployment_failures: u32,
+    render_failures: u32,
+    rejected_verifications: u32,
+    /// Failures since last successful render.
+    consecutive_failures: u32,
+    quarantined_until: Option<SystemTime>,
+}
+
+impl PeerHistory {
+    /// Records failure; returns true when the peer got quarantined.
+    fn fail(&mut self, failure: PeerFailure, now: SystemTime) -> bool {
+        match failure {
+            PeerFailure::Deployment => self.deployment_failures += 1,
+            PeerFailure::Render => self.render_failures += 1,
+            PeerFailure::Verification => self.rejected_verifications += 1,
+        }
+        self.consecutive_failures += 1;
+        if self.consecutive_failures < QUARANTINE_AFTER {
+            return false;
+        }
+        self.consecutive_failures = 0;
+        self.quarantined_until = Some(now + QUARANTINE_PERIOD);
+        true
+    }
+
+    fn is_quarantined(&self, now: SystemTime) -> bool {
+        self.quarantined_until.map(|t| t > now).unwrap_or(false)
+    }
+
+    fn score(&self, node_id: NodeId, now: SystemTime) -> PeerScore {
+        let unix_secs = |t: SystemTime| {
+            t.duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        };
+
+        PeerScore {
+            node_id,
+            subtasks_done: self.subtasks_done,
+            throughput: self.throughput(),
+            deployment_failures: self.deployment_failures,
+            render_failures: self.render_failures,
+            rejected_verifications: self.rejected_verifications,
+            consecutive_failures: self.consecutive_failures,
+            quarantined_until: self
+                .quarantined_until
+                .filter(|&t| t > now)
+                .map(unix_secs),
+            last_used: self.last_used.map(unix_secs),
+        }
+    }
+
+    fn throughput(&self) -> Option<f64> {
+        let secs = self.render_time.as_secs() as f64
+            + f64::from(self.render_time.subsec_millis()) / 1000.0;
+        if self.subtasks_done == 0 || secs <= 0.0 {
+            None
+        } else {
+            Some(f64::from(self.subtasks_done) / secs)
         }
     }
 }
 
+/// Health of a peer, as exposed over the HTTP API. Times are unix timestamps in seconds.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerScore {
+    pub node_id: NodeId,
+    pub subtasks_done: u32,
+    /// Subtasks rendered per second.
+    pub throughput: Option<f64>,
+    pub deployment_failures: u32,
+    pub render_failures: u32,
+    pub rejected_verifications: u32,
+    pub consecutive_failures: u32,
+    pub quarantined_until: Option<u64>,
+    pub last_used: Option<u64>,
+}
+
+/// Cores and RAM needed by a single blender container.
+const CORES_PER_SLOT: u32 = 4;
+const MEMORY_PER_SLOT: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Estimated performance score of a single core.
+const PERFORMANCE_PER_CORE: f32 = 200.0;
+
+/// Hardware of a hub peer, as reported in its `sys_info`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PeerHardware {
+    pub cores: u32,
+    /// Total RAM in bytes.
+    pub memory: u64,
+    /// Free RAM in bytes, at the time of reading.
+    pub free_memory: u64,
+    /// Available disk space in bytes.
+    pub disk: u64,
+}
+
+impl PeerHardware {
+    pub fn from_sys_info(sys_info: &serde_json::Value) -> Self {
+        let num = |v: &serde_json::Value| v.as_u64().unwrap_or(0);
+
+        PeerHardware {
+            cores: num(&sys_info["num_cores"]).max(num(&sys_info["numCores"])) as u32,
+            memory: num(&sys_info["ram"]["total"]),
+            free_memory: num(&sys_info["ram"]["free"]),
+            disk: num(&sys_info["disk"]["available"]),
+        }
+    }
+
+    pub fn performance(&self) -> f32 {
+        self.cores as f32 * PERFORMANCE_PER_CORE
+    }
+
+    /// Number of blender containers the peer can run at once. Unknown
+    /// hardware counts as a single slot.
+    pub fn slots(&self) -> usize {
+        let by_cores = if self.cores == 0 { 1 } else { self.cores / CORES_PER_SLOT };
+        let by_memory = if self.memory == 0 { 1 } else { self.memory / MEMORY_PER_SLOT };
+
+        (by_cores as u64).min(by_memory).max(1) as usize
+    }
+
+    /// Hardware of the largest peer, field by field.
+    pub fn max<'a>(peers: impl IntoIterator<Item = &'a PeerHardware>) -> Option<PeerHardware> {
+        peers.into_iter().fold(None, |acc, p| {
+            let acc = acc.unwrap_or_default();
+            Some(PeerHardware {
+                cores: acc.cores.max(p.cores),
+                memory: acc.memory.max(p.memory),
+                free_memory: acc.free_memory.max(p.free_memory),
+                disk: acc.disk.max(p.disk),
+            })
+        })
+    }
+}
+
+/// Hardware of all peers of the hub session.
+pub fn session_hardware(
+    session_id: u64,
+) -> impl Future<Item = HashMap<NodeId, PeerHardware>, Error = gu_client::error::Error> {
+    HubConnection::default()
+        .hub_session(session_id)
+        .list_peers()
+        .and_then(|peers| {
+            future::join_all(
+                peers
+                    .map(|p| {
+                        let node_id = p.node_id;
+                        peer_hardware(node_id).map(move |hw| (node_id, hw))
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .map(|peers| peers.into_iter().collect())
+}
+
+pub fn peer_hardware(
+    node_id: NodeId,
+) -> impl Future<Item = PeerHardware, Error = gu_client::error::Error> {
+    HubConnection::default()
+        .peer(node_id)
+        .info()
+        .map(|details| {
+            let sys_info =
+                serde_json::to_value(&details.sys_info).unwrap_or(serde_json::Value::Null);
+            PeerHardware::from_sys_info(&sys_info)
+        })
+}
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
 impl Actor for WorkMan {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let restored = self.restore_reservations();
+        if !restored.is_empty() {
+            self.reconcile(restored, ctx);
+        }
+        ctx.run_interval(SWEEP_INTERVAL, |act, _| act.sweep_expired());
+    }
 }
 
 impl WorkMan {
-    fn is_free_to_use(&self, peer_id: NodeId) -> bool {
-        self.reservations
+    /// Capacity of the peer: configured slots, else derived from its hardware.
+    fn capacity(&self, peer_id: NodeId, slots_per_peer: Option<usize>) -> usize {
+        slots_per_peer.unwrap_or_else(|| {
+            self.hardware
+                .get(&peer_id)
+                .map(PeerHardware::slots)
+                .unwrap_or(1)
+        })
+    }
+
+    /// Peer has a free slot; slots already held by the same task count as well.
+    fn is_free_to_use(&self, peer_id: NodeId, slots_per_peer: Option<usize>) -> bool {
+        if self.is_quarantined(peer_id) {
+            return false;
+        }
+        match self.reservations.get(&peer_id) {
+            Some(slots) => slots.used() < self.capacity(peer_id, slots_per_peer),
+            None => true,
+        }
+    }
+
+    fn is_quarantined(&self, peer_id: NodeId) -> bool {
+        self.history
             .get(&peer_id)
-            .map(|r| !r.is_valid())
-            .unwrap_or(true)
+            .map(|h| h.is_quarantined(SystemTime::now()))
+            .unwrap_or(false)
+    }
+
+    /// Runs query on the reservation database. Failures are only logged, since
+    /// reservations are kept in memory anyway.
+    fn with_db<T>(
+        &self,
+        what: &str,
+        query: impl FnOnce(&SqliteConnection) -> diesel::QueryResult<T>,
+    ) -> Option<T> {
+        let db = self.db.as_ref()?;
+        query(db)
+            .map_err(|e| log::error!("unable to {}: {}", what, e))
+            .ok()
+    }
+
+    fn reserve_slot(
+        &mut self,
+        peer_id: NodeId,
+        task_id: String,
+        deadline: u64,
+        owner: Option<String>,
+    ) -> SlotId {
+        let slots = self.reservations.entry(peer_id).or_default();
+        let index = slots.free_index();
+        // expired reservation of the slot may still wait for sweep
+        slots
+            .reservations
+            .retain(|r| r.is_valid() || r.index != index);
+        slots
+            .reservations
+            .push(Reservation::new(index, task_id, deadline, owner));
+
+        let reservation = slots.reservations.last().unwrap().to_model(peer_id);
+        self.with_db("store reservation", |db| {
+            model::insert_reservation(db, &reservation)
+        });
+        SlotId {
+            node_id: peer_id,
+            index,
+        }
+    }
+
+    /// Releases the peer slot held by the task.
+    fn release_slot(&mut self, slot: SlotId, task_id: &str) -> bool {
+        let released = match self.reservations.get_mut(&slot.node_id) {
+            Some(slots) => {
+                let released = slots.release(slot.index, task_id).is_some();
+                if slots.reservations.is_empty() {
+                    self.reservations.remove(&slot.node_id);
+                }
+                released
+            }
+            None => false,
+        };
+        if released {
+            self.with_db("remove reservation", |db| {
+                model::delete_reservation(db, &node_hex(slot.node_id), slot.index as i32, task_id)
+            });
+        }
+        released
+    }
+
+    /// Loads reservations stored by previous run; returns the restored ones
+    /// with tags of their deployments.
+    fn restore_reservations(&mut self) -> Vec<(SlotId, String, Vec<String>)> {
+        let now = chrono::Utc::now().naive_utc();
+        self.with_db("remove expired reservations", |db| {
+            model::delete_expired_reservations(db, now)
+        });
+        let stored = self
+            .with_db("load reservations", |db| model::active_reservations(db, now))
+            .unwrap_or_default();
+
+        let mut restored = Vec::new();
+        for r in stored {
+            match Reservation::from_model(r) {
+                Ok((node_id, reservation)) => {
+                    let slot = SlotId {
+                        node_id,
+                        index: reservation.index,
+                    };
+                    log::info!(
+                        "restored reservation of {:?} for task {} until {:?}",
+                        slot,
+                        reservation.task_id,
+                        reservation.reserved_until
+                    );
+                    restored.push((
+                        slot,
+                        reservation.task_id.clone(),
+                        reservation.deployment_tags(),
+                    ));
+                    self.reservations
+                        .entry(node_id)
+                        .or_default()
+                        .reservations
+                        .push(reservation);
+                }
+                Err(e) => log::warn!("skipping stored reservation: {}", e),
+            }
+        }
+        restored
+    }
+
+    /// Drops restored reservations of peers which run no deployment created for
+    /// them anymore; peers still running one stay reserved until the deadline.
+    ///
+    /// Workers of restored tasks release their reservations and destroy their
+    /// deployments on start, so this guards peers of tasks which are not restored.
+    fn reconcile(
+        &mut self,
+        restored: Vec<(SlotId, String, Vec<String>)>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        ctx.spawn(
+            self.connection
+                .list_peers()
+                .into_actor(self)
+                .map_err(|e, _, _| log::warn!("unable to reconcile reservations: {}", e))
+                .map(move |peers, act: &mut WorkMan, _| {
+                    let deployments: HashMap<NodeId, Vec<HashSet<String>>> = peers
+                        .map(|p| {
+                            let sessions = p
+                                .sessions
+                                .into_iter()
+                                .map(|s| s.tags.into_iter().collect())
+                                .collect();
+                            (p.node_id, sessions)
+                        })
+                        .collect();
+                    let deployed = |slot: &SlotId, tags: &[String]| {
+                        deployments.get(&slot.node_id).map_or(false, |sessions| {
+                            sessions
+                                .iter()
+                                .any(|session_tags| tags.iter().all(|t| session_tags.contains(t)))
+                        })
+                    };
+
+                    for (slot, task_id, tags) in restored {
+                        if !deployed(&slot, &tags) && act.release_slot(slot, &task_id) {
+                            log::info!(
+                                "peer {:?} runs no deployment, reservation for task {} dropped",
+                                slot,
+                                task_id
+                            );
+                        }
+                    }
+                }),
+        );
+    }
+
+    fn assign_subtask(&mut self, slot: SlotId, task_id: &str, subtask_id: Option<String>) {
+        let reservation = self.reservations.get_mut(&slot.node_id).and_then(|slots| {
+            slots
+                .reservations
+                .iter_mut()
+                .find(|r| r.index == slot.index && r.task_id == task_id)
+        });
+        if let Some(reservation) = reservation {
+            reservation.subtask_id = subtask_id.clone();
+            self.with_db("store reserved subtask", |db| {
+                model::update_reservation_subtask(
+                    db,
+                    &node_hex(slot.node_id),
+                    slot.index as i32,
+                    task_id,
+                    subtask_id.as_ref().map(String::as_str),
+                )
+            });
+        }
+    }
+
+    fn candidate(&self, node_id: NodeId, hardware: Option<PeerHardware>) -> Candidate {
+        let history = self.history.get(&node_id);
+
+        Candidate {
+            node_id,
+            hardware,
+            last_used: history.and_then(|h| h.last_used),
+            throughput: history.and_then(PeerHistory::throughput),
+        }
+    }
+
+    /// Selector of the session, recreated when the session changes its strategy.
+    fn selector(&mut self, session_id: u64, strategy: SelectionStrategy) -> &mut dyn PeerSelector {
+        let entry = self
+            .selectors
+            .entry(session_id)
+            .or_insert_with(|| (strategy, strategy.selector()));
+        if entry.0 != strategy {
+            *entry = (strategy, strategy.selector());
+        }
+        entry.1.as_mut()
+    }
+
+    fn sweep_expired(&mut self) {
+        self.reservations.retain(|node_id, slots| {
+            slots.reservations.retain(|r| {
+                if r.is_valid() {
+                    true
+                } else {
+                    log::info!(
+                        "reservation of {:?} slot {} for task {} (subtask {:?}) expired",
+                        node_id,
+                        r.index,
+                        r.task_id,
+                        r.subtask_id
+                    );
+                    false
+                }
+            });
+            !slots.reservations.is_empty()
+        });
+        let now = naive_time(SystemTime::now());
+        self.with_db("remove expired reservations", |db| {
+            model::delete_expired_reservations(db, now)
+        });
     }
 }
 
@@ -73,27 +732,36 @@ struct GiveMeNode {
 }
 
 impl Message for GiveMeNode {
-    type Result = Result<NodeId, NoFreeNode>;
+    type Result = Result<SlotId, NoFreeNode>;
 }
 
 struct GiveMeSessionNode {
     session_id: u64,
     task_id: String,
+    /// Owner tag of deployments the task creates on the peer.
+    owner: String,
     deadline: u64,
+    strategy: SelectionStrategy,
+    slots_per_peer: Option<usize>,
+    /// Peers not to be reserved, e.g. ones the subtask already failed on.
+    exclude: Vec<NodeId>,
 }
 
 impl Message for GiveMeSessionNode {
-    type Result = Result<NodeId, NoFreeNode>;
+    type Result = Result<SlotId, NoFreeNode>;
 }
 
-struct FreeNode(NodeId);
+struct FreeNode {
+    slot: SlotId,
+    task_id: String,
+}
 
 impl Message for FreeNode {
     type Result = ();
 }
 
 impl Handler<GiveMeNode> for WorkMan {
-    type Result = ActorResponse<Self, NodeId, NoFreeNode>;
+    type Result = ActorResponse<Self, SlotId, NoFreeNode>;
 
     fn handle(&mut self, msg: GiveMeNode, _ctx: &mut Self::Context) -> Self::Result {
         ActorResponse::r#async(
@@ -104,15 +772,13 @@ impl Handler<GiveMeNode> for WorkMan {
                 .and_then(move |peers, act, _ctx| {
                     let c: Vec<NodeId> = peers
                         .map(|p| p.node_id)
-                        .filter(|&p| act.is_free_to_use(p))
+                        .filter(|&p| act.is_free_to_use(p, None))
                         .collect();
 
                     let mut rng = rand::thread_rng();
 
                     if let Some(&it) =  rng.choose(c.as_ref()) {
-                        act.reservations
-                            .insert(it.clone(), Reservation::new(msg.task_id, msg.deadline));
-                        fut::ok(it)
+                        fut::ok(act.reserve_slot(it, msg.task_id, msg.deadline, None))
                     } else {
                         fut::err(NoFreeNode)
                     }
@@ -122,9 +788,13 @@ impl Handler<GiveMeNode> for WorkMan {
 }
 
 impl Handler<GiveMeSessionNode> for WorkMan {
-    type Result = ActorResponse<Self, NodeId, NoFreeNode>;
+    type Result = ActorResponse<Self, SlotId, NoFreeNode>;
 
     fn handle(&mut self, msg: GiveMeSessionNode, _ctx: &mut Self::Context) -> Self::Result {
+        let strategy = msg.strategy;
+        let slots_per_peer = msg.slots_per_peer;
+        let exclude = msg.exclude.clone();
+
         ActorResponse::r#async(
             self.connection
                 .hub_session(msg.session_id)
@@ -132,55 +802,213 @@ impl Handler<GiveMeSessionNode> for WorkMan {
                 .into_actor(self)
                 .map_err(|_, _act, _ctx| NoFreeNode)
                 .and_then(move |peers, act, _ctx| {
-                    let c: Vec<NodeId> = peers
+                    let peers: Vec<NodeId> = peers
                         .map(|p| p.node_id)
-                        .filter(|&p| act.is_free_to_use(p))
+                        .filter(|p| !exclude.contains(p))
                         .collect();
 
-                    let mut rng = rand::thread_rng();
+                    // free memory changes, so it is read again whenever the strategy uses it
+                    let peers = future::join_all(peers.into_iter().map(|node_id| {
+                        let hardware = act.hardware.get(&node_id).cloned();
+                        let needs_hardware = strategy.needs_hardware()
+                            || (slots_per_peer.is_none() && hardware.is_none());
 
-                    if let Some(&it) = rng.choose(c.as_ref()) {
-                        act.reservations
-                            .insert(it.clone(), Reservation::new(msg.task_id, msg.deadline));
-                        fut::ok(it)
-                    } else {
-                        fut::err(NoFreeNode)
+                        if needs_hardware {
+                            future::Either::A(
+                                peer_hardware(node_id)
+                                    .then(move |hw| Ok::<_, NoFreeNode>((node_id, hw.ok()))),
+                            )
+                        } else {
+                            future::Either::B(future::ok((node_id, hardware)))
+                        }
+                    }));
+                    peers.into_actor(act)
+                })
+                .and_then(move |peers: Vec<(NodeId, Option<PeerHardware>)>, act, _ctx| {
+                    for &(node_id, hw) in &peers {
+                        if let Some(hw) = hw {
+                            act.hardware.insert(node_id, hw);
+                        }
+                    }
+
+                    // peers could be reserved by other tasks meanwhile
+                    let candidates: Vec<Candidate> = peers
+                        .into_iter()
+                        .filter(|&(node_id, _)| act.is_free_to_use(node_id, slots_per_peer))
+                        .map(|(node_id, hw)| act.candidate(node_id, hw))
+                        .collect();
+
+                    match act.selector(msg.session_id, strategy).select(&candidates) {
+                        Some(it) => {
+                            let slot = act.reserve_slot(it, msg.task_id, msg.deadline, Some(msg.owner));
+                            act.history.entry(it).or_default().last_used = Some(SystemTime::now());
+                            fut::ok(slot)
+                        }
+                        None => fut::err(NoFreeNode),
                     }
                 }),
         )
     }
 }
 
+/// Render of a subtask finished successfully on the peer.
+struct RenderDone {
+    node_id: NodeId,
+    render_time: Duration,
+}
+
+impl Message for RenderDone {
+    type Result = ();
+}
+
+impl Handler<RenderDone> for WorkMan {
+    type Result = ();
+
+    fn handle(&mut self, msg: RenderDone, _ctx: &mut Self::Context) -> Self::Result {
+        let history = self.history.entry(msg.node_id).or_default();
+        history.subtasks_done += 1;
+        history.render_time += msg.render_time;
+        history.consecutive_failures = 0;
+    }
+}
+
+struct PeerFailed {
+    node_id: NodeId,
+    failure: PeerFailure,
+}
+
+impl Message for PeerFailed {
+    type Result = ();
+}
+
+impl Handler<PeerFailed> for WorkMan {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerFailed, _ctx: &mut Self::Context) -> Self::Result {
+        let history = self.history.entry(msg.node_id).or_default();
+        if history.fail(msg.failure, SystemTime::now()) {
+            log::warn!(
+                "peer {:?} quarantined for {:?} after {:?} failure",
+                msg.node_id,
+                QUARANTINE_PERIOD,
+                msg.failure
+            );
+        }
+    }
+}
+
+struct Scoreboard;
+
+impl Message for Scoreboard {
+    type Result = Vec<PeerScore>;
+}
+
+impl Handler<Scoreboard> for WorkMan {
+    type Result = MessageResult<Scoreboard>;
+
+    fn handle(&mut self, _msg: Scoreboard, _ctx: &mut Self::Context) -> Self::Result {
+        let now = SystemTime::now();
+        let mut scores: Vec<PeerScore> = self
+            .history
+            .iter()
+            .map(|(&node_id, history)| history.score(node_id, now))
+            .collect();
+        scores.sort_by(|a, b| b.subtasks_done.cmp(&a.subtasks_done));
+        MessageResult(scores)
+    }
+}
+
 impl Handler<FreeNode> for WorkMan {
     type Result = ();
 
-    fn handle(&mut self, msg: FreeNode, ctx: &mut Self::Context) -> Self::Result {
-        self.reservations.remove(&msg.0);
+    fn handle(&mut self, msg: FreeNode, _ctx: &mut Self::Context) -> Self::Result {
+        if self.release_slot(msg.slot, &msg.task_id) {
+            log::debug!("peer {:?} slot released by task {}", msg.slot, msg.task_id);
+        }
+    }
+}
+
+/// Releases all slots of the task, e.g. ones restored for a task whose worker restarts.
+struct FreeTask(String);
+
+impl Message for FreeTask {
+    type Result = ();
+}
+
+impl Handler<FreeTask> for WorkMan {
+    type Result = ();
+
+    fn handle(&mut self, msg: FreeTask, _ctx: &mut Self::Context) -> Self::Result {
+        let held: Vec<SlotId> = self
+            .reservations
+            .iter()
+            .flat_map(|(&node_id, slots)| {
+                slots
+                    .reservations
+                    .iter()
+                    .filter(|r| r.task_id == msg.0)
+                    .map(move |r| SlotId {
+                        node_id,
+                        index: r.index,
+                    })
+            })
+            .collect();
+        for slot in held {
+            if self.release_slot(slot, &msg.0) {
+                log::info!("peer {:?} released from previous run of task {}", slot, msg.0);
+            }
+        }
+    }
+}
+
+struct AssignSubtask {
+    slot: SlotId,
+    task_id: String,
+    subtask_id: Option<String>,
+}
+
+impl Message for AssignSubtask {
+    type Result = ();
+}
+
+impl Handler<AssignSubtask> for WorkMan {
+    type Result = ();
+
+    fn handle(&mut self, msg: AssignSubtask, _ctx: &mut Self::Context) -> Self::Result {
+        self.assign_subtask(msg.slot, &msg.task_id, msg.subtask_id)
     }
 }
 
 pub fn reserve_for_session(
     session_id: u64,
     task_id: &str,
+    owner: String,
     deadline: u64,
-) -> impl Future<Item = NodeId, Error = NoFreeNode> {
+    strategy: SelectionStrategy,
+    slots_per_peer: Option<usize>,
+    exclude: Vec<NodeId>,
+) -> impl Future<Item = SlotId, Error = NoFreeNode> {
     let task = task_id.to_owned();
     WorkMan::from_registry()
         .send(GiveMeSessionNode {
             session_id,
             task_id: task.clone(),
+            owner,
             deadline,
+            strategy,
+            slots_per_peer,
+            exclude,
         })
         .then(move |r| match r {
-            Ok(Ok(node_id)) => {
+            Ok(Ok(slot)) => {
                 log::info!(
                     "reserved peer {:?} for subtask {:?} until {:?}",
-                    node_id,
+                    slot,
                     task,
                     deadline
                 );
 
-                Ok(node_id)
+                Ok(slot)
             }
             Err(e) => {
                 log::error!("reservation error: {}", e);
@@ -190,7 +1018,7 @@ pub fn reserve_for_session(
         })
 }
 
-pub fn reserve(task_id: &str, deadline: u64) -> impl Future<Item = NodeId, Error = NoFreeNode> {
+pub fn reserve(task_id: &str, deadline: u64) -> impl Future<Item = SlotId, Error = NoFreeNode> {
     let task = task_id.to_owned();
     WorkMan::from_registry()
         .send(GiveMeNode {
@@ -198,15 +1026,15 @@ pub fn reserve(task_id: &str, deadline: u64) -> impl Future<Item = NodeId, Error
             deadline,
         })
         .then(move |r| match r {
-            Ok(Ok(node_id)) => {
+            Ok(Ok(slot)) => {
                 log::info!(
                     "reserved peer {:?} for subtask {:?} until {:?}",
-                    node_id,
+                    slot,
                     task,
                     deadline
                 );
 
-                Ok(node_id)
+                Ok(slot)
             }
             Err(e) => {
                 log::error!("reservation error: {}", e);
@@ -216,6 +1044,199 @@ pub fn reserve(task_id: &str, deadline: u64) -> impl Future<Item = NodeId, Error
         })
 }
 
-pub fn release(task_id: &str, node_id: NodeId) {
-    WorkMan::from_registry().do_send(FreeNode(node_id))
+/// Records successful render, used to rank peers by throughput.
+pub fn record_render(node_id: NodeId, render_time: Duration) {
+    WorkMan::from_registry().do_send(RenderDone {
+        node_id,
+        render_time,
+    })
+}
+
+/// Records failure of the peer; peers failing repeatedly are not reserved for a while.
+pub fn record_failure(node_id: NodeId, failure: PeerFailure) {
+    WorkMan::from_registry().do_send(PeerFailed { node_id, failure })
+}
+
+/// Failure history of all peers used so far.
+pub fn scoreboard() -> impl Future<Item = Vec<PeerScore>, Error = MailboxError> {
+    WorkMan::from_registry().send(Scoreboard)
+}
+
+pub fn release_task(task_id: &str) {
+    WorkMan::from_registry().do_send(FreeTask(task_id.to_owned()))
+}
+
+/// Records subtask computed on the reserved peer slot; `None` when the slot is idle.
+pub fn assign_subtask(task_id: &str, slot: SlotId, subtask_id: Option<&str>) {
+    WorkMan::from_registry().do_send(AssignSubtask {
+        slot,
+        task_id: task_id.to_owned(),
+        subtask_id: subtask_id.map(ToOwned::to_owned),
+    })
+}
+
+pub fn release(task_id: &str, slot: SlotId) {
+    WorkMan::from_registry().do_send(FreeNode {
+        slot,
+        task_id: task_id.to_owned(),
+    })
+}
+
+/// Like `release`, but resolves once the slot is free again.
+pub fn release_wait(task_id: &str, slot: SlotId) -> impl Future<Item = (), Error = ()> {
+    WorkMan::from_registry()
+        .send(FreeNode {
+            slot,
+            task_id: task_id.to_owned(),
+        })
+        .map_err(move |e| log::error!("unable to release slot {:?}: {}", slot, e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_peer_hardware() {
+        let hw = PeerHardware::from_sys_info(&serde_json::json!({
+            "num_cores": 8,
+            "ram": { "free": 1024, "total": 16_000_000_000u64 },
+            "disk": { "available": 100_000_000_000u64, "total": 500_000_000_000u64 },
+        }));
+        assert_eq!(
+            hw,
+            PeerHardware { cores: 8, memory: 16_000_000_000, free_memory: 1024, disk: 100_000_000_000 }
+        );
+        assert_eq!(hw.performance(), 1600.0);
+
+        let small = PeerHardware { cores: 2, memory: 32_000_000_000, free_memory: 0, disk: 0 };
+        assert_eq!(
+            PeerHardware::max(&[hw, small]),
+            Some(PeerHardware {
+                cores: 8,
+                memory: 32_000_000_000,
+                free_memory: 1024,
+                disk: 100_000_000_000
+            })
+        );
+        assert_eq!(PeerHardware::max(&[]), None);
+
+        assert_eq!(hw.slots(), 2);
+        assert_eq!(small.slots(), 1);
+        assert_eq!(PeerHardware::default().slots(), 1);
+        let big = PeerHardware { cores: 64, memory: 128 * 1024 * 1024 * 1024, ..PeerHardware::default() };
+        assert_eq!(big.slots(), 16);
+    }
+
+    #[test]
+    fn test_peer_slots() {
+        let deadline = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600;
+        let mut slots = PeerSlots::default();
+        slots.reservations.push(Reservation::new(0, "t1".into(), deadline, None));
+        slots.reservations.push(Reservation::new(1, "t1".into(), deadline, None));
+        slots.reservations.push(Reservation::new(2, "t3".into(), 0, None));
+
+        assert_eq!(slots.used(), 2);
+        // slot of expired reservation is free again
+        assert_eq!(slots.free_index(), 2);
+
+        assert!(slots.release(0, "t3").is_none());
+        assert!(slots.release(0, "t1").is_some());
+        assert!(slots.release(0, "t1").is_none());
+        assert_eq!(slots.used(), 1);
+        assert_eq!(slots.free_index(), 0);
+        assert_eq!(PeerHardware::from_sys_info(&serde_json::Value::Null), PeerHardware::default());
+    }
+
+    fn candidates() -> Vec<Candidate> {
+        let hw = |free_memory| PeerHardware { free_memory, ..PeerHardware::default() };
+        let used = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+
+        vec![
+            Candidate { node_id: NodeId::from([1u8; 20]), hardware: Some(hw(100)), last_used: used(20), throughput: Some(0.5) },
+            Candidate { node_id: NodeId::from([2u8; 20]), hardware: Some(hw(300)), last_used: used(10), throughput: Some(2.0) },
+            Candidate { node_id: NodeId::from([3u8; 20]), hardware: None, last_used: used(30), throughput: Some(1.0) },
+        ]
+    }
+
+    #[test]
+    fn test_selectors() {
+        let mut c = candidates();
+
+        assert_eq!(LeastRecentlyUsedSelector.select(&c), Some(c[1].node_id));
+        assert_eq!(BestThroughputSelector.select(&c), Some(c[1].node_id));
+        assert_eq!(MostFreeMemorySelector.select(&c), Some(c[1].node_id));
+
+        let mut round_robin = RoundRobinSelector::default();
+        let picked: Vec<_> = (0..4).filter_map(|_| round_robin.select(&c)).collect();
+        assert_eq!(picked, vec![c[0].node_id, c[1].node_id, c[2].node_id, c[0].node_id]);
+
+        assert!(c.iter().any(|it| Some(it.node_id) == RandomSelector.select(&c)));
+
+        c[0].last_used = None;
+        c[2].throughput = None;
+        assert_eq!(LeastRecentlyUsedSelector.select(&c), Some(c[0].node_id));
+        assert_eq!(BestThroughputSelector.select(&c), Some(c[2].node_id));
+
+        assert_eq!(RoundRobinSelector::default().select(&[]), None);
+        assert_eq!(MostFreeMemorySelector.select(&[]), None);
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut history = PeerHistory::default();
+
+        assert!(!history.fail(PeerFailure::Deployment, now));
+        assert!(!history.fail(PeerFailure::Render, now));
+        assert!(!history.is_quarantined(now));
+
+        assert!(history.fail(PeerFailure::Verification, now));
+        assert!(history.is_quarantined(now));
+        assert!(!history.is_quarantined(now + QUARANTINE_PERIOD));
+
+        let score = history.score(NodeId::from([1u8; 20]), now);
+        assert_eq!(score.deployment_failures, 1);
+        assert_eq!(score.render_failures, 1);
+        assert_eq!(score.rejected_verifications, 1);
+        assert_eq!(score.quarantined_until, Some(1_000_600));
+        assert_eq!(
+            history.score(NodeId::from([1u8; 20]), now + QUARANTINE_PERIOD).quarantined_until,
+            None
+        );
+    }
+
+    #[test]
+    fn test_reservation_model() {
+        let node_id = NodeId::from([0xabu8; 20]);
+        let mut reservation = Reservation::new(
+            2,
+            "t1".into(),
+            1_555_000_000,
+            Some(blender::owner_tag("0x01", 7)),
+        );
+        reservation.subtask_id = Some("s1".into());
+
+        let stored = reservation.to_model(node_id);
+        assert_eq!(stored.node_id, "ab".repeat(20));
+        assert_eq!(stored.slot, 2);
+
+        let (restored_node_id, restored) = Reservation::from_model(stored).unwrap();
+        assert_eq!(restored_node_id, node_id);
+        assert_eq!(restored.index, 2);
+        assert_eq!(restored.task_id, "t1");
+        assert_eq!(restored.subtask_id, Some("s1".into()));
+        assert_eq!(restored.reserved_until, reservation.reserved_until);
+        assert_eq!(
+            restored.deployment_tags(),
+            vec![
+                "gu:brass:owner=0x01/7".to_string(),
+                "gu:brass:task=t1".to_string()
+            ]
+        );
+    }
 }